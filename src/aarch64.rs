@@ -0,0 +1,148 @@
+//! AArch64 FP exceptions are enabled with the trap enable bits in FPCR.
+//! See: Arm Architecture Reference Manual for A-profile architecture:
+//!
+//! # D1.3.4 Floating-point exceptions and exception traps
+//!
+//! The FPCR.{IDE, IXE, UFE, OFE, DZE, IOE} bits are trap enable bits. Support for trapping of
+//! floating-point exceptions is optional. If it is not supported, the trap enable bits are RAZ/WI
+//! and the cumulative exception bits in FPSR are set instead.
+//!
+//! ----
+//!
+//! Most Cortex-A cores do not implement trapping (it is rare outside of server cores), so the
+//! trap enables are read back after they are written. If they are ignored, the process falls
+//! back to polling the cumulative bits in FPSR with [`crate::checkpoint`].
+//!
+//! Only the low 32 bits of FPCR and FPSR are defined, so both fit in one [`Saved`] value.
+
+use crate::backend::{FpuBackend, Saved};
+use crate::policy::Exceptions;
+use core::arch::asm;
+
+/// Offset of the trap enable bits from the cumulative bits, except for Input Denormal (FPCR)
+const FPCR_TRAP_SHIFT: u32 = 8;
+/// Input Denormal trap enable (FPCR)
+const FPCR_IDE: u64 = 1 << 15;
+/// All trap enable bits (FPCR)
+const FPCR_TRAPS: u64 = 0x1f << FPCR_TRAP_SHIFT | FPCR_IDE;
+/// Input Denormal cumulative bit (FPSR)
+const FPSR_IDC: u64 = 1 << 7;
+/// All cumulative exception bits (FPSR)
+const FPSR_CUMULATIVE: u64 = 0x1f | FPSR_IDC;
+
+/// Convert exceptions to their cumulative bits in FPSR.
+///
+/// The order of [`Exceptions`] follows x86, which puts the denormal operand exception second. The
+/// remaining exceptions are in the same order as the FPSR cumulative bits.
+fn fpsr_bits(exceptions: Exceptions) -> u64 {
+    let bits = u64::from(exceptions.bits());
+    let mut fpsr = bits & 0x1 | (bits >> 1) & 0x1e;
+    if bits & 0x2 != 0 {
+        fpsr |= FPSR_IDC;
+    }
+    fpsr
+}
+
+/// Convert the cumulative bits in FPSR to exceptions.
+fn from_fpsr_bits(fpsr: u64) -> Exceptions {
+    let mut bits = fpsr & 0x1 | (fpsr & 0x1e) << 1;
+    if fpsr & FPSR_IDC != 0 {
+        bits |= 0x2;
+    }
+    Exceptions::from_bits(bits as u8)
+}
+
+/// Convert exceptions to their trap enable bits in FPCR.
+fn fpcr_traps(exceptions: Exceptions) -> u64 {
+    let fpsr = fpsr_bits(exceptions);
+    (fpsr & 0x1f) << FPCR_TRAP_SHIFT | if fpsr & FPSR_IDC != 0 { FPCR_IDE } else { 0 }
+}
+
+/// The floating-point unit, configured with FPCR.
+pub(crate) struct Fpcr;
+
+impl FpuBackend for Fpcr {
+    fn name(&self) -> &'static str {
+        "fpcr"
+    }
+
+    /// The trap enables are written and read back. They read as zero if trapping is not
+    /// implemented.
+    fn is_supported(&self) -> bool {
+        let fpcr = read_fpcr();
+        // SAFETY: The trap enables are restored before any operation can raise an exception.
+        unsafe {
+            write_fpcr(fpcr | FPCR_TRAPS);
+            let supported = read_fpcr() & FPCR_TRAPS == FPCR_TRAPS;
+            write_fpcr(fpcr);
+
+            supported
+        }
+    }
+
+    unsafe fn enable(&self, exceptions: Exceptions) {
+        write_fpsr(read_fpsr() & !FPSR_CUMULATIVE);
+        write_fpcr(read_fpcr() | fpcr_traps(exceptions));
+    }
+
+    unsafe fn disable(&self, exceptions: Exceptions) {
+        write_fpcr(read_fpcr() & !fpcr_traps(exceptions));
+    }
+
+    fn read_enabled(&self) -> Exceptions {
+        let fpcr = read_fpcr();
+        let idc = if fpcr & FPCR_IDE != 0 { FPSR_IDC } else { 0 };
+        from_fpsr_bits((fpcr >> FPCR_TRAP_SHIFT) & 0x1f | idc)
+    }
+
+    fn save(&self) -> Saved {
+        Saved(read_fpcr() << 32 | read_fpsr() & 0xffff_ffff)
+    }
+
+    unsafe fn restore(&self, saved: Saved) {
+        write_fpsr(saved.0 & 0xffff_ffff);
+        write_fpcr(saved.0 >> 32);
+    }
+
+    fn read_status(&self) -> Exceptions {
+        from_fpsr_bits(read_fpsr() & FPSR_CUMULATIVE)
+    }
+
+    unsafe fn clear_status(&self) {
+        write_fpsr(read_fpsr() & !FPSR_CUMULATIVE);
+    }
+}
+
+/// Read the floating-point control register.
+fn read_fpcr() -> u64 {
+    let fpcr;
+    // SAFETY: Reads a system register.
+    unsafe { asm!("mrs {}, fpcr", out(reg) fpcr, options(nomem, nostack, preserves_flags)) };
+    fpcr
+}
+
+/// Write the floating-point control register.
+///
+/// # Safety
+///
+/// Changes the floating point environment of the current thread.
+unsafe fn write_fpcr(fpcr: u64) {
+    asm!("msr fpcr, {}", in(reg) fpcr, options(nomem, nostack, preserves_flags));
+}
+
+/// Read the floating-point status register.
+fn read_fpsr() -> u64 {
+    let fpsr;
+    // SAFETY: Reads a system register.
+    unsafe { asm!("mrs {}, fpsr", out(reg) fpsr, options(nomem, nostack, preserves_flags)) };
+    fpsr
+}
+
+/// Write the floating-point status register.
+///
+/// # Safety
+///
+/// Changes the floating point environment of the current thread.
+unsafe fn write_fpsr(fpsr: u64) {
+    asm!("msr fpsr, {}", in(reg) fpsr, options(nomem, nostack, preserves_flags));
+}
@@ -0,0 +1,165 @@
+//! Enable floating point exceptions on every thread in the process.
+//!
+//! Each thread is sent a private real-time signal with `tgkill`. The signal handler runs on the
+//! receiving thread and unmasks exceptions in the interrupted context. Modifying the FPU directly
+//! would not work; the kernel restores the FPU state saved in the signal frame when the handler
+//! returns.
+
+use crate::{backend, handler, policy, watchdog, x86_64, ARMED, UNGUARDED};
+use log::debug;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use std::{ffi::c_void, fs, io, mem};
+
+// How long to wait for every thread to acknowledge the signal.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+// The real-time signal number chosen by `install`, or zero.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+// `si_code` for signals sent with `sigqueue` or `rt_tgsigqueueinfo`.
+const SI_QUEUE: libc::c_int = -1;
+
+// The generation of the current call in the upper 32 bits, and the number of threads that
+// acknowledged it in the lower 32 bits. Each call sends its generation with the signal, and the
+// signal handler only counts acknowledgements for the current generation, so that a thread that
+// responds after a previous call timed out is not counted toward the next one.
+static ACKS: AtomicU64 = AtomicU64::new(0);
+
+// Serializes `signal_all_threads` so that acknowledgements are not shared between callers.
+static BROADCAST: Mutex<()> = Mutex::new(());
+
+/// See [`crate::signal_all_threads`].
+pub(crate) unsafe fn signal_all_threads() -> io::Result<()> {
+    let _guard = BROADCAST.lock().unwrap_or_else(PoisonError::into_inner);
+
+    handler::install()?;
+    crate::arm();
+
+    let signal = install()?;
+    let pid = libc::getpid();
+    let this = libc::gettid();
+    let internal = handler::internal_threads();
+
+    let generation = (ACKS.load(Ordering::SeqCst) >> 32).wrapping_add(1) & u64::from(u32::MAX);
+    ACKS.store(generation << 32, Ordering::SeqCst);
+
+    let mut sent = 0;
+    for entry in fs::read_dir("/proc/self/task")? {
+        let Some(tid) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<libc::pid_t>().ok())
+        else {
+            continue;
+        };
+        if tid == this || internal.contains(&tid) {
+            continue;
+        }
+
+        if queue(pid, tid, signal, generation) == 0 {
+            sent += 1;
+        } else {
+            // The thread may have exited since the directory was read.
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ESRCH) {
+                return Err(err);
+            }
+        }
+    }
+
+    debug!("Sent signal {signal} to {sent} threads");
+
+    let start = Instant::now();
+    let acks = || (ACKS.load(Ordering::Acquire) & u64::from(u32::MAX)) as usize;
+    while acks() < sent {
+        if start.elapsed() >= TIMEOUT {
+            let missing = sent - acks();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{missing} of {sent} threads did not respond (is the signal blocked?)"),
+            ));
+        }
+
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    Ok(())
+}
+
+/// The `siginfo_t` layout of a queued signal.
+#[repr(C)]
+struct QueueInfo {
+    signo: libc::c_int,
+    errno: libc::c_int,
+    code: libc::c_int,
+    fields: QueueFields,
+}
+
+/// The `_rt` member of the `siginfo_t` fields union. It contains a pointer, so it is aligned like
+/// the union.
+#[repr(C)]
+struct QueueFields {
+    pid: libc::pid_t,
+    uid: libc::uid_t,
+    value: libc::sigval,
+}
+
+/// Send `signal` to thread `tid` with `generation` as its value, like `tgkill`.
+unsafe fn queue(
+    pid: libc::pid_t,
+    tid: libc::pid_t,
+    signal: libc::c_int,
+    generation: u64,
+) -> libc::c_long {
+    let mut info: libc::siginfo_t = mem::zeroed();
+    let queued = (&mut info as *mut libc::siginfo_t).cast::<QueueInfo>();
+    (*queued).signo = signal;
+    (*queued).code = SI_QUEUE;
+    (*queued).fields = QueueFields {
+        pid,
+        uid: libc::getuid(),
+        value: libc::sigval {
+            sival_ptr: generation as usize as *mut c_void,
+        },
+    };
+
+    libc::syscall(libc::SYS_rt_tgsigqueueinfo, pid, tid, signal, &info)
+}
+
+/// Install the handler on the first real-time signal that nobody else is using.
+unsafe fn install() -> io::Result<libc::c_int> {
+    let signal = SIGNAL.load(Ordering::Relaxed);
+    if signal != 0 {
+        return Ok(signal);
+    }
+
+    let signal = handler::claim_realtime_signal(handler)?;
+    debug!("Using signal {signal} to enable FPU exceptions on other threads");
+    SIGNAL.store(signal, Ordering::Relaxed);
+
+    Ok(signal)
+}
+
+// SAFETY: This is a signal handler. It only touches the interrupted context, const-initialized
+// thread locals, and an atomic.
+extern "C" fn handler(_signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    debug_assert!(backend::is_selected());
+
+    // Exceptions are masked on purpose inside `unguarded`, and enabling them would be undone by
+    // its guard anyway. The thread is still acknowledged.
+    if !UNGUARDED.get() {
+        if !backend::is_polling() {
+            unsafe { x86_64::enable_in_context(context.cast(), policy::enabled()) };
+        }
+        unsafe { watchdog::arm_in_context(context.cast()) };
+        ARMED.set(true);
+    }
+
+    // SAFETY: The signal was queued by `queue`.
+    let generation = unsafe { (*info.cast::<QueueInfo>()).fields.value.sival_ptr } as u64;
+    let _ = ACKS.fetch_update(Ordering::Release, Ordering::Acquire, |acks| {
+        (acks >> 32 == generation).then_some(acks + 1)
+    });
+}
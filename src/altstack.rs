@@ -0,0 +1,128 @@
+//! Alternate signal stacks for armed threads.
+//!
+//! The `SIGFPE` handler captures a backtrace, which needs more stack than a thread that faults
+//! deep in a recursion may have left. The handler runs on an alternate signal stack instead, which
+//! is mapped when the thread is armed (except by [`crate::signal_all_threads`]). The standard
+//! library installs a small alternate stack on its threads to report stack overflows; it is
+//! replaced if it is too small for the handler.
+
+use log::debug;
+use std::cell::Cell;
+use std::{io, ptr};
+
+/// The usable size of the alternate stack. This is enough for the handler to capture a full
+/// backtrace, with plenty to spare.
+const SIZE: usize = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+const MAP_STACK: libc::c_int = libc::MAP_STACK;
+#[cfg(not(target_os = "linux"))]
+const MAP_STACK: libc::c_int = 0;
+
+thread_local! {
+    // The mapping (including the guard page) of the alternate stack installed on this thread, or
+    // null. It is unmapped by `OWNER` when the thread exits.
+    static MAPPING: Cell<*mut libc::c_void> = const { Cell::new(ptr::null_mut()) };
+
+    // Unmaps the alternate stack when the thread exits.
+    static OWNER: Owner = const { Owner };
+}
+
+/// Install an alternate signal stack on the current thread, if it does not already have one that
+/// is large enough. The stack is unmapped when the thread exits.
+///
+/// This is not async-signal-safe, so threads that are armed in a signal handler run the handler
+/// on their own stack.
+pub(crate) fn install() {
+    // SAFETY: The stack is only installed on the current thread, which is not running on its
+    // alternate signal stack.
+    match unsafe { map() } {
+        // Register the destructor.
+        Ok(true) => OWNER.with(|_| ()),
+        Ok(false) => (),
+        Err(err) => debug!("Unable to install an alternate signal stack: {err}"),
+    }
+}
+
+/// Map an alternate signal stack and install it on the current thread, if it does not already
+/// have one that is large enough. Returns `true` if a stack was installed.
+///
+/// # Safety
+///
+/// Must not be called while the current thread is running on its alternate signal stack.
+unsafe fn map() -> io::Result<bool> {
+    let mut current: libc::stack_t = std::mem::zeroed();
+    if libc::sigaltstack(ptr::null(), &mut current) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if current.ss_flags & libc::SS_DISABLE == 0 && current.ss_size >= SIZE {
+        return Ok(false);
+    }
+
+    let page = page_size();
+    let mapping = libc::mmap(
+        ptr::null_mut(),
+        page + SIZE,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | MAP_STACK,
+        -1,
+        0,
+    );
+    if mapping == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The stack grows down, so the guard page is at the start of the mapping.
+    let stack = libc::stack_t {
+        ss_sp: mapping.cast::<u8>().add(page).cast(),
+        ss_flags: 0,
+        ss_size: SIZE,
+    };
+    if libc::mprotect(mapping, page, libc::PROT_NONE) != 0
+        || libc::sigaltstack(&stack, ptr::null_mut()) != 0
+    {
+        let err = io::Error::last_os_error();
+        libc::munmap(mapping, page + SIZE);
+        return Err(err);
+    }
+
+    // The stack that the standard library installed is unmapped by the standard library.
+    MAPPING.set(mapping);
+
+    Ok(true)
+}
+
+/// The size of a memory page.
+fn page_size() -> usize {
+    // SAFETY: `sysconf` has no preconditions.
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// See [`OWNER`].
+struct Owner;
+
+impl Drop for Owner {
+    fn drop(&mut self) {
+        let mapping = MAPPING.replace(ptr::null_mut());
+        if mapping.is_null() {
+            return;
+        }
+
+        let page = page_size();
+        // SAFETY: The thread is exiting, so it is not running on its alternate stack. The stack
+        // is disabled before it is unmapped, unless another stack replaced it.
+        unsafe {
+            let mut current: libc::stack_t = std::mem::zeroed();
+            libc::sigaltstack(ptr::null(), &mut current);
+            if current.ss_sp == mapping.cast::<u8>().add(page).cast() {
+                let disable = libc::stack_t {
+                    ss_sp: ptr::null_mut(),
+                    ss_flags: libc::SS_DISABLE,
+                    ss_size: 0,
+                };
+                libc::sigaltstack(&disable, ptr::null_mut());
+            }
+            libc::munmap(mapping, page + SIZE);
+        }
+    }
+}
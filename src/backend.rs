@@ -0,0 +1,606 @@
+//! Interchangeable implementations of the floating point environment operations.
+//!
+//! Each backend controls one floating point unit (or one library interface to it). The backends
+//! are selected once per process, the first time they are needed. On `x86` and `x86_64`, the SSE
+//! and x87 units are controlled separately, and both are selected.
+//!
+//! The selection can be overridden with the `BATMAN_BACKEND` environment variable, which is
+//! mostly useful for testing:
+//!
+//! - `glibc`: Use `feenableexcept` and friends, on Linux with glibc.
+//! - `polling`: Do not trap exceptions. Poll the status flags with [`crate::checkpoint`].
+
+use crate::policy::Exceptions;
+use log::{debug, warn};
+use std::sync::OnceLock;
+
+/// The environment variable that overrides the backend selection.
+const ENV_VAR: &str = "BATMAN_BACKEND";
+
+/// The most backends that are active at once (SSE and x87).
+const MAX_BACKENDS: usize = 2;
+
+/// The configuration of a backend, saved by [`FpuBackend::save`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Saved(pub(crate) u64);
+
+/// Operations on the floating point environment of the current thread.
+///
+/// All methods are async-signal-safe.
+pub(crate) trait FpuBackend: Sync {
+    /// A short name for log messages.
+    fn name(&self) -> &'static str;
+
+    /// Returns `true` if exceptions can be trapped. Some processors ignore the trap enables.
+    fn is_supported(&self) -> bool;
+
+    /// Returns `true` if exceptions are detected with [`crate::checkpoint`] instead of trapped.
+    fn is_polling(&self) -> bool {
+        false
+    }
+
+    /// Clear the status flags and trap the exceptions.
+    ///
+    /// # Safety
+    ///
+    /// Changes the floating point environment of the current thread.
+    unsafe fn enable(&self, exceptions: Exceptions);
+
+    /// Stop trapping the exceptions. The status flags are left alone.
+    ///
+    /// # Safety
+    ///
+    /// Changes the floating point environment of the current thread.
+    unsafe fn disable(&self, exceptions: Exceptions);
+
+    /// Read the exceptions that are trapped.
+    fn read_enabled(&self) -> Exceptions;
+
+    /// Save the trap enables and status flags.
+    fn save(&self) -> Saved;
+
+    /// Restore the configuration that was saved by [`FpuBackend::save`].
+    ///
+    /// # Safety
+    ///
+    /// Changes the floating point environment of the current thread.
+    unsafe fn restore(&self, saved: Saved);
+
+    /// Read the status flags.
+    fn read_status(&self) -> Exceptions;
+
+    /// Clear the status flags.
+    ///
+    /// # Safety
+    ///
+    /// Changes the floating point environment of the current thread.
+    unsafe fn clear_status(&self);
+
+    /// Get the trapped exceptions that were raised in a signal handler context.
+    ///
+    /// # Safety
+    ///
+    /// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+    #[cfg(target_os = "linux")]
+    unsafe fn decode_context(&self, _context: *mut libc::ucontext_t) -> Exceptions {
+        Exceptions::empty()
+    }
+
+    /// Read the status flags of the interrupted thread in a signal handler context.
+    ///
+    /// The default reads the live status flags. Most kernels leave them alone when a signal
+    /// handler is called, but Linux `x86` and `x86_64` reset them.
+    ///
+    /// # Safety
+    ///
+    /// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+    #[cfg(target_os = "linux")]
+    unsafe fn status_in_context(&self, _context: *mut libc::ucontext_t) -> Exceptions {
+        self.read_status()
+    }
+
+    /// Read the enabled exceptions of the interrupted thread in a signal handler context.
+    ///
+    /// The default reads the live configuration, like [`FpuBackend::status_in_context`].
+    ///
+    /// # Safety
+    ///
+    /// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+    #[cfg(target_os = "linux")]
+    unsafe fn enabled_in_context(&self, _context: *mut libc::ucontext_t) -> Exceptions {
+        self.read_enabled()
+    }
+}
+
+/// Detects exceptions by polling the status flags of another backend.
+pub(crate) struct Polling(&'static dyn FpuBackend);
+
+impl FpuBackend for Polling {
+    fn name(&self) -> &'static str {
+        "polling"
+    }
+
+    fn is_supported(&self) -> bool {
+        true
+    }
+
+    fn is_polling(&self) -> bool {
+        true
+    }
+
+    unsafe fn enable(&self, _exceptions: Exceptions) {
+        self.0.clear_status();
+    }
+
+    unsafe fn disable(&self, _exceptions: Exceptions) {}
+
+    fn read_enabled(&self) -> Exceptions {
+        Exceptions::empty()
+    }
+
+    fn save(&self) -> Saved {
+        self.0.save()
+    }
+
+    unsafe fn restore(&self, saved: Saved) {
+        self.0.restore(saved);
+    }
+
+    fn read_status(&self) -> Exceptions {
+        self.0.read_status()
+    }
+
+    unsafe fn clear_status(&self) {
+        self.0.clear_status();
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn status_in_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+        self.0.status_in_context(context)
+    }
+}
+
+/// The C99 `<fenv.h>` interface, with the glibc extensions for trapping exceptions.
+#[cfg(all(
+    target_os = "linux",
+    target_env = "gnu",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64"
+    )
+))]
+pub(crate) struct Glibc;
+
+/// Polls the status flags with glibc, on processors without a native backend.
+#[cfg(all(
+    target_os = "linux",
+    target_env = "gnu",
+    any(
+        target_arch = "riscv64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64"
+    )
+))]
+static GLIBC_POLLING: Polling = Polling(&Glibc);
+
+#[cfg(all(
+    target_os = "linux",
+    target_env = "gnu",
+    any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64",
+        target_arch = "powerpc",
+        target_arch = "powerpc64"
+    )
+))]
+mod glibc {
+    use super::{FpuBackend, Glibc, Saved};
+    use crate::policy::Exceptions;
+    use libc::c_int;
+
+    extern "C" {
+        fn feenableexcept(excepts: c_int) -> c_int;
+        fn fedisableexcept(excepts: c_int) -> c_int;
+        fn fegetexcept() -> c_int;
+        fn fetestexcept(excepts: c_int) -> c_int;
+        fn feclearexcept(excepts: c_int) -> c_int;
+        fn feraiseexcept(excepts: c_int) -> c_int;
+    }
+
+    /// The `FE_*` constant for each exception, in the bit order of [`Exceptions`]. Zero if the
+    /// exception is not supported.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const FE: [c_int; 6] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20];
+    #[cfg(target_arch = "aarch64")]
+    const FE: [c_int; 6] = [0x01, 0, 0x02, 0x04, 0x08, 0x10];
+    #[cfg(target_arch = "riscv64")]
+    const FE: [c_int; 6] = [0x10, 0, 0x08, 0x04, 0x02, 0x01];
+    #[cfg(any(target_arch = "powerpc", target_arch = "powerpc64"))]
+    const FE: [c_int; 6] = [
+        0x2000_0000,
+        0,
+        0x0400_0000,
+        0x1000_0000,
+        0x0800_0000,
+        0x0200_0000,
+    ];
+
+    /// All exceptions.
+    const FE_ALL_EXCEPT: c_int = FE[0] | FE[1] | FE[2] | FE[3] | FE[4] | FE[5];
+
+    fn to_fe(exceptions: Exceptions) -> c_int {
+        FE.iter()
+            .enumerate()
+            .filter(|&(bit, _)| exceptions.bits() & (1 << bit) != 0)
+            .fold(0, |fe, (_, &flag)| fe | flag)
+    }
+
+    /// The exceptions that glibc does not support on this processor.
+    fn unsupported() -> Exceptions {
+        FE.iter()
+            .enumerate()
+            .filter(|&(_, &flag)| flag == 0)
+            .fold(Exceptions::empty(), |exceptions, (bit, _)| {
+                Exceptions::from_bits(exceptions.bits() | 1 << bit)
+            })
+    }
+
+    fn from_fe(fe: c_int) -> Exceptions {
+        let bits = FE
+            .iter()
+            .enumerate()
+            .filter(|&(_, &flag)| flag != 0 && fe & flag != 0)
+            .fold(0, |bits, (bit, _)| bits | 1 << bit);
+        Exceptions::from_bits(bits)
+    }
+
+    impl FpuBackend for Glibc {
+        fn name(&self) -> &'static str {
+            "glibc"
+        }
+
+        fn is_supported(&self) -> bool {
+            // `feenableexcept` fails on processors that ignore the trap enables.
+            let divide_by_zero = FE[2];
+            // SAFETY: The trap enable is restored before any operation can raise an exception.
+            unsafe {
+                let previous = fegetexcept();
+                let supported =
+                    feenableexcept(divide_by_zero) != -1 && fegetexcept() & divide_by_zero != 0;
+                fedisableexcept(divide_by_zero & !previous);
+
+                supported
+            }
+        }
+
+        unsafe fn enable(&self, exceptions: Exceptions) {
+            feclearexcept(FE_ALL_EXCEPT);
+            feenableexcept(to_fe(exceptions));
+        }
+
+        unsafe fn disable(&self, exceptions: Exceptions) {
+            fedisableexcept(to_fe(exceptions));
+        }
+
+        /// Exceptions that glibc does not support on this processor cannot be trapped, so they are
+        /// never reported as masked.
+        fn read_enabled(&self) -> Exceptions {
+            // SAFETY: This only reads the floating point environment.
+            let enabled = from_fe(unsafe { fegetexcept() });
+            Exceptions::from_bits(enabled.bits() | unsupported().bits())
+        }
+
+        fn save(&self) -> Saved {
+            // SAFETY: These only read the floating point environment.
+            let (enabled, status) = unsafe { (fegetexcept(), fetestexcept(FE_ALL_EXCEPT)) };
+            Saved(u64::from(from_fe(enabled).bits()) << 8 | u64::from(from_fe(status).bits()))
+        }
+
+        unsafe fn restore(&self, saved: Saved) {
+            // The status flags are raised while all traps are disabled, so they do not trap.
+            fedisableexcept(FE_ALL_EXCEPT);
+            feclearexcept(FE_ALL_EXCEPT);
+            feraiseexcept(to_fe(Exceptions::from_bits(saved.0 as u8)));
+            feenableexcept(to_fe(Exceptions::from_bits((saved.0 >> 8) as u8)));
+        }
+
+        fn read_status(&self) -> Exceptions {
+            // SAFETY: This only reads the floating point environment.
+            from_fe(unsafe { fetestexcept(FE_ALL_EXCEPT) })
+        }
+
+        unsafe fn clear_status(&self) {
+            feclearexcept(FE_ALL_EXCEPT);
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        unsafe fn decode_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+            // glibc configures both the SSE and x87 units.
+            let sse = crate::x86_64::sse_raised_in_context(context);
+            let x87 = crate::x86_64::x87_raised_in_context(context);
+            Exceptions::from_bits(sse.bits() | x87.bits())
+        }
+
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        unsafe fn status_in_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+            let sse = crate::x86_64::sse_status_in_context(context);
+            let x87 = crate::x86_64::x87_status_in_context(context);
+            Exceptions::from_bits(sse.bits() | x87.bits())
+        }
+
+        /// Only exceptions that are enabled in both units are reported. Unsupported exceptions
+        /// are reported like [`FpuBackend::read_enabled`].
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        unsafe fn enabled_in_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+            let sse = crate::x86_64::sse_enabled_in_context(context);
+            let x87 = crate::x86_64::x87_enabled_in_context(context);
+            Exceptions::from_bits(sse.bits() & x87.bits() | unsupported().bits())
+        }
+    }
+}
+
+// The backends that were selected for this process.
+static ACTIVE: OnceLock<&'static [&'static dyn FpuBackend]> = OnceLock::new();
+
+/// Get the backends that were selected for this process.
+///
+/// Selecting the backends is not async-signal-safe, so they are selected before any signal
+/// handler is registered (see [`is_selected`]).
+pub(crate) fn active() -> &'static [&'static dyn FpuBackend] {
+    ACTIVE.get_or_init(select)
+}
+
+/// Returns `true` if the backends were already selected. Signal handlers assert this before they
+/// call [`active`].
+pub(crate) fn is_selected() -> bool {
+    ACTIVE.get().is_some()
+}
+
+/// Returns `true` if exceptions are detected with [`crate::checkpoint`] instead of trapped.
+pub(crate) fn is_polling() -> bool {
+    active().iter().any(|backend| backend.is_polling())
+}
+
+/// Clear the status flags and trap the exceptions on the current thread.
+///
+/// # Safety
+///
+/// Changes the floating point environment of the current thread.
+pub(crate) unsafe fn enable(exceptions: Exceptions) {
+    for backend in active() {
+        backend.enable(exceptions);
+    }
+}
+
+/// Get the given exceptions that are not trapped on the current thread, e.g. because other code
+/// masked them. Backends that poll are not checked.
+pub(crate) fn masked(exceptions: Exceptions) -> Exceptions {
+    let mut bits = 0;
+    for backend in active().iter().filter(|backend| !backend.is_polling()) {
+        bits |= exceptions.bits() & !backend.read_enabled().bits();
+    }
+
+    Exceptions::from_bits(bits)
+}
+
+/// Read and clear the status flags of the given exceptions on the current thread.
+pub(crate) fn take_status(exceptions: Exceptions) -> Exceptions {
+    let mut bits = 0;
+    for backend in active() {
+        bits |= backend.read_status().bits() & exceptions.bits();
+        // SAFETY: Clearing status flags cannot cause an exception. The flags of other exceptions
+        // are not reported by polling, so they can be discarded.
+        unsafe { backend.clear_status() };
+    }
+
+    Exceptions::from_bits(bits)
+}
+
+/// Read the status flags of the given exceptions of the interrupted thread in a signal handler
+/// context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn status_in_context(
+    context: *mut libc::ucontext_t,
+    exceptions: Exceptions,
+) -> Exceptions {
+    let mut bits = 0;
+    for backend in active() {
+        bits |= backend.status_in_context(context).bits() & exceptions.bits();
+    }
+
+    Exceptions::from_bits(bits)
+}
+
+/// Get the trapped exceptions that were raised in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn raised_in_context(context: *mut libc::ucontext_t) -> Exceptions {
+    let mut bits = 0;
+    for backend in active() {
+        bits |= backend.decode_context(context).bits();
+    }
+
+    Exceptions::from_bits(bits)
+}
+
+/// Returns `true` if the interrupted thread in a signal handler context has the configuration
+/// that `batman` enables: every active backend traps exactly `exceptions`.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn is_enabled_in_context(
+    context: *mut libc::ucontext_t,
+    exceptions: Exceptions,
+) -> bool {
+    active()
+        .iter()
+        .all(|backend| backend.enabled_in_context(context) == exceptions)
+}
+
+/// Disables all floating point exceptions until it is dropped.
+///
+/// Dropping the guard restores the previous configuration, and discards any status flags that
+/// were raised while it was alive.
+pub(crate) struct MaskGuard {
+    saved: [Saved; MAX_BACKENDS],
+}
+
+impl MaskGuard {
+    pub(crate) fn new() -> Self {
+        debug_assert!(active().len() <= MAX_BACKENDS);
+
+        let mut saved = [Saved::default(); MAX_BACKENDS];
+        for (saved, backend) in saved.iter_mut().zip(active()) {
+            *saved = backend.save();
+            // SAFETY: Disabling traps cannot cause an exception.
+            unsafe { backend.disable(Exceptions::ALL) };
+        }
+
+        Self { saved }
+    }
+}
+
+impl Drop for MaskGuard {
+    fn drop(&mut self) {
+        for (&saved, backend) in self.saved.iter().zip(active()) {
+            // SAFETY: This restores the configuration that was saved by `MaskGuard::new`.
+            unsafe { backend.restore(saved) };
+        }
+    }
+}
+
+/// Select the backends for this process.
+fn select() -> &'static [&'static dyn FpuBackend] {
+    let backends = match std::env::var(ENV_VAR).as_deref() {
+        Ok("glibc") => glibc().unwrap_or_else(|| {
+            warn!("{ENV_VAR}=glibc is not supported on this platform");
+            native()
+        }),
+        Ok("polling") => polling(),
+        Ok(name) => {
+            warn!("Unknown {ENV_VAR}: {name}");
+            native()
+        }
+        Err(_) => native(),
+    };
+
+    if backends.is_empty() {
+        warn!("Floating point exceptions cannot be detected on this platform");
+        return backends;
+    }
+    if backends.iter().any(|backend| !backend.is_supported()) {
+        warn!(
+            "The processor does not trap floating point exceptions, falling back to polling with \
+            `batman::checkpoint()`"
+        );
+        return polling();
+    }
+
+    for backend in backends {
+        debug!("Selected the {} floating point backend", backend.name());
+    }
+
+    backends
+}
+
+/// The backends that control the hardware directly.
+fn native() -> &'static [&'static dyn FpuBackend] {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    ))]
+    return &[&crate::x86_64::Sse, &crate::x86_64::X87];
+
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(target_feature = "sse")
+    ))]
+    return &[&crate::x86_64::X87];
+
+    #[cfg(target_arch = "aarch64")]
+    return &[&crate::aarch64::Fpcr];
+
+    #[cfg(all(
+        any(target_arch = "riscv32", target_arch = "riscv64"),
+        target_feature = "f"
+    ))]
+    return &[&crate::riscv::Fflags];
+
+    // The status flags are not available on this platform (e.g. WebAssembly), unless glibc can
+    // read them.
+    #[allow(unreachable_code)]
+    glibc().unwrap_or_default()
+}
+
+/// The glibc backend, if it is available.
+fn glibc() -> Option<&'static [&'static dyn FpuBackend]> {
+    #[cfg(all(
+        target_os = "linux",
+        target_env = "gnu",
+        any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "riscv64",
+            target_arch = "powerpc",
+            target_arch = "powerpc64"
+        )
+    ))]
+    return Some(&[&Glibc]);
+
+    #[allow(unreachable_code)]
+    None
+}
+
+/// Polling backends for the status flags of the native backends.
+fn polling() -> &'static [&'static dyn FpuBackend] {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse"
+    ))]
+    return &[&Polling(&crate::x86_64::Sse), &Polling(&crate::x86_64::X87)];
+
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        not(target_feature = "sse")
+    ))]
+    return &[&Polling(&crate::x86_64::X87)];
+
+    #[cfg(target_arch = "aarch64")]
+    return &[&Polling(&crate::aarch64::Fpcr)];
+
+    #[cfg(all(
+        any(target_arch = "riscv32", target_arch = "riscv64"),
+        target_feature = "f"
+    ))]
+    return &[&Polling(&crate::riscv::Fflags)];
+
+    #[cfg(all(
+        target_os = "linux",
+        target_env = "gnu",
+        any(
+            target_arch = "riscv64",
+            target_arch = "powerpc",
+            target_arch = "powerpc64"
+        )
+    ))]
+    return &[&GLIBC_POLLING];
+
+    #[allow(unreachable_code)]
+    &[]
+}
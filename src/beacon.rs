@@ -0,0 +1,182 @@
+//! One-shot wakeups between the signal handler and the tracer thread.
+//!
+//! The signal handler can only use async-signal-safe functions, which rules out `Thread::unpark`
+//! and the standard library's synchronization primitives. A beacon is a pipe on unix-like OSes
+//! (`write` and `poll` are async-signal-safe), and an event object on Windows.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::sync::atomic::AtomicI32;
+#[cfg(windows)]
+use std::sync::atomic::AtomicIsize;
+#[cfg(windows)]
+use windows_sys::Win32::System::Threading;
+
+/// A flag that one thread sets, and another thread waits for.
+pub(crate) struct Beacon {
+    /// Set by [`Beacon::send`]. The wakeup only says that it is worth checking.
+    sent: AtomicBool,
+    /// The read and write ends of the pipe, or -1 before [`Beacon::init`].
+    #[cfg(unix)]
+    pipe: [AtomicI32; 2],
+    /// The manual-reset event, or 0 before [`Beacon::init`].
+    #[cfg(windows)]
+    event: AtomicIsize,
+}
+
+impl Beacon {
+    pub(crate) const fn new() -> Self {
+        Self {
+            sent: AtomicBool::new(false),
+            #[cfg(unix)]
+            pipe: [AtomicI32::new(-1), AtomicI32::new(-1)],
+            #[cfg(windows)]
+            event: AtomicIsize::new(0),
+        }
+    }
+
+    /// Create the OS resources. This must be called before the beacon is used on another thread.
+    pub(crate) fn init(&self) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            let mut fds = [-1; 2];
+            // SAFETY: `fds` has room for both ends. They are close-on-exec, so that child
+            // processes do not keep them open. The read end does not block, so that waking up
+            // can drain it without waiting.
+            unsafe {
+                if libc::pipe(fds.as_mut_ptr()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                for fd in fds {
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                }
+                libc::fcntl(fds[0], libc::F_SETFL, libc::O_NONBLOCK);
+            }
+            self.pipe[0].store(fds[0], Ordering::Release);
+            self.pipe[1].store(fds[1], Ordering::Release);
+        }
+
+        #[cfg(windows)]
+        {
+            // SAFETY: The event is unnamed, with default security.
+            let event =
+                unsafe { Threading::CreateEventW(std::ptr::null(), 1, 0, std::ptr::null()) };
+            if event == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            self.event.store(event, Ordering::Release);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the beacon was sent.
+    pub(crate) fn is_sent(&self) -> bool {
+        self.sent.load(Ordering::Acquire)
+    }
+
+    /// Set the flag, and wake the waiting thread. This is async-signal-safe.
+    pub(crate) fn send(&self) {
+        self.sent.store(true, Ordering::Release);
+
+        // SAFETY: The pipe and the event are never closed.
+        #[cfg(unix)]
+        unsafe {
+            let byte = 1_u8;
+            libc::write(
+                self.pipe[1].load(Ordering::Acquire),
+                (&byte as *const u8).cast(),
+                1,
+            );
+        }
+
+        #[cfg(windows)]
+        unsafe {
+            Threading::SetEvent(self.event.load(Ordering::Acquire));
+        }
+    }
+
+    /// Wait until the beacon is sent.
+    pub(crate) fn wait(&self) {
+        while !self.wait_timeout(Duration::MAX) {}
+    }
+
+    /// Wait until the beacon is sent, or the timeout expires. Returns `true` if the beacon was
+    /// sent. This is async-signal-safe.
+    ///
+    /// Only one thread may wait for each beacon.
+    pub(crate) fn wait_timeout(&self, timeout: Duration) -> bool {
+        #[cfg(unix)]
+        {
+            let deadline = now().saturating_add(timeout);
+            while !self.is_sent() {
+                let remaining = deadline.saturating_sub(now());
+                if remaining.is_zero() {
+                    return false;
+                }
+
+                let fd = self.pipe[0].load(Ordering::Acquire);
+                let mut poll = libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // The wait is restarted if it is interrupted by another signal, or if the pipe
+                // was written by a process that was forked from this one (which has its own
+                // flag). Draining the pipe prevents the latter from spinning.
+                // SAFETY: The pipe is never closed.
+                unsafe {
+                    if libc::poll(&mut poll, 1, millis(remaining)) > 0 {
+                        let mut byte = 0_u8;
+                        libc::read(fd, (&mut byte as *mut u8).cast(), 1);
+                    }
+                }
+            }
+        }
+
+        #[cfg(windows)]
+        if !self.is_sent() {
+            // SAFETY: The event is never closed.
+            unsafe {
+                Threading::WaitForSingleObject(
+                    self.event.load(Ordering::Acquire),
+                    millis(timeout) as u32,
+                )
+            };
+        }
+
+        #[cfg(not(any(unix, windows)))]
+        {
+            let mut seconds = timeout.as_secs();
+            while !self.is_sent() && seconds > 0 {
+                // SAFETY: `sleep` is async-signal-safe.
+                unsafe { libc::sleep(1) };
+                seconds -= 1;
+            }
+        }
+
+        self.is_sent()
+    }
+}
+
+/// The monotonic clock. `clock_gettime` is async-signal-safe.
+#[cfg(unix)]
+fn now() -> Duration {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid `timespec`.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+/// Convert a timeout to milliseconds, rounding up so that short timeouts do not busy-wait.
+#[cfg(any(unix, windows))]
+fn millis(timeout: Duration) -> i32 {
+    timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+}
@@ -0,0 +1,57 @@
+//! Scoped control of how subnormal numbers are handled.
+
+/// How subnormal numbers are handled by SSE instructions. See [`with_denormals`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum DenormalMode {
+    /// Subnormal numbers are handled as IEEE 754 requires. This is the default.
+    #[default]
+    Preserve,
+    /// Subnormal results are replaced with zero (FTZ). This only applies when
+    /// [`Exception::Underflow`](crate::Exception::Underflow) is masked.
+    FlushToZero,
+    /// Subnormal operands are treated as zero (DAZ).
+    /// [`Exception::Denormal`](crate::Exception::Denormal) is not raised.
+    DenormalsAreZero,
+    /// Both [`DenormalMode::FlushToZero`] and [`DenormalMode::DenormalsAreZero`].
+    FlushAll,
+}
+
+/// Run a closure with a denormal handling mode on the current thread.
+///
+/// Subnormal numbers are a common performance cliff, e.g. in audio DSP where feedback loops decay
+/// toward zero. Flushing them to zero avoids the slow path at the cost of IEEE 754 conformance.
+/// The previous mode is restored afterward, even if `f` panics. Calls can be nested.
+///
+/// ```
+/// use batman::DenormalMode;
+/// use std::hint::black_box;
+///
+/// let tiny = batman::with_denormals(DenormalMode::FlushToZero, || {
+///     black_box(f64::MIN_POSITIVE) / black_box(4.0)
+/// });
+/// # #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+/// assert_eq!(tiny, 0.0);
+/// ```
+///
+/// To find the code that needs flushing, count [`Exception::Denormal`](crate::Exception::Denormal)
+/// and [`Exception::Underflow`](crate::Exception::Underflow) with [`set_action`](crate::set_action)
+/// and inspect [`sites`](crate::sites).
+///
+/// Unlike the rest of `batman`, this function also works when debug assertions are disabled. It
+/// only affects SSE instructions, and it only calls `f` on platforms other than `x86` and `x86_64`.
+pub fn with_denormals<F, R>(mode: DenormalMode, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let _guard = crate::x86_64::DenormalGuard::new(
+        matches!(mode, DenormalMode::FlushToZero | DenormalMode::FlushAll),
+        matches!(mode, DenormalMode::DenormalsAreZero | DenormalMode::FlushAll),
+    );
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let _ = mode;
+
+    f()
+}
@@ -0,0 +1,144 @@
+//! Callbacks that run before the process is terminated by a floating point exception.
+
+use crate::Exception;
+use std::fmt;
+use std::time::Duration;
+
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(debug_assertions)]
+use std::sync::{mpsc, Mutex, PoisonError};
+
+/// The time that [`on_fatal`] gives each callback.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[cfg(debug_assertions)]
+type Callback = Box<dyn FnOnce(&Report) + Send>;
+
+// The registered callbacks and their timeouts, in registration order. The tracer thread takes
+// them when the process is terminated.
+#[cfg(debug_assertions)]
+static CALLBACKS: Mutex<Vec<(Duration, Callback)>> = Mutex::new(Vec::new());
+
+// The sum of the timeouts of the registered callbacks, in milliseconds. The signal handler waits
+// this much longer for the tracer thread.
+#[cfg(debug_assertions)]
+static BUDGET_MS: AtomicU64 = AtomicU64::new(0);
+
+/// A description of the floating point exception that terminates the process, passed to the
+/// callbacks registered with [`on_fatal`].
+#[derive(Clone, Debug, Default)]
+#[non_exhaustive]
+pub struct Report {
+    exceptions: Vec<Exception>,
+}
+
+impl Report {
+    #[cfg(debug_assertions)]
+    pub(crate) fn new(exceptions: Vec<Exception>) -> Self {
+        Self { exceptions }
+    }
+
+    /// The exceptions that were raised. This is empty when they are not known, e.g. on platforms
+    /// where the signal handler cannot read the floating point state of the thread, or when the
+    /// report was raised by [`checkpoint`](crate::checkpoint) or [`verify`](crate::verify).
+    pub fn exceptions(&self) -> &[Exception] {
+        &self.exceptions
+    }
+}
+
+/// Formats the report like `floating point exception (invalid operation)`.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "floating point exception")?;
+        for (i, exception) in self.exceptions.iter().enumerate() {
+            let separator = if i == 0 { " (" } else { ", " };
+            write!(f, "{separator}{exception}")?;
+        }
+        if !self.exceptions.is_empty() {
+            write!(f, ")")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Register a callback that runs before a floating point exception terminates the process.
+///
+/// Destructors do not run when the process is terminated, so this is the last chance to flush
+/// log buffers, save a checkpoint of long-running work, or close a recording file. Callbacks run
+/// in registration order after the backtrace is printed, each on its own thread, and each one is
+/// given one second to finish. See [`on_fatal_with_timeout`].
+///
+/// ```
+/// batman::on_fatal(|report| eprintln!("Saving the simulation after a {report}"));
+/// ```
+///
+/// This function is a no-op when debug assertions are disabled.
+pub fn on_fatal<F>(callback: F)
+where
+    F: FnOnce(&Report) + Send + 'static,
+{
+    on_fatal_with_timeout(DEFAULT_TIMEOUT, callback);
+}
+
+/// Like [`on_fatal`], with the time that the callback is given to finish.
+///
+/// This is best-effort. The thread that raised the exception is stopped in the signal handler,
+/// and it may hold locks that the callback needs (e.g. the `stderr` lock, or a lock in the
+/// allocator). A callback that does not finish in time is abandoned, the remaining callbacks run,
+/// and then the process is terminated. Callbacks do not have floating point exceptions enabled.
+///
+/// This function is a no-op when debug assertions are disabled.
+pub fn on_fatal_with_timeout<F>(timeout: Duration, callback: F)
+where
+    F: FnOnce(&Report) + Send + 'static,
+{
+    #[cfg(debug_assertions)]
+    {
+        let mut callbacks = CALLBACKS.lock().unwrap_or_else(PoisonError::into_inner);
+        callbacks.push((timeout, Box::new(callback)));
+
+        let timeout = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+        BUDGET_MS
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |budget| {
+                Some(budget.saturating_add(timeout))
+            })
+            .ok();
+    }
+
+    #[cfg(not(debug_assertions))]
+    let _ = (timeout, callback);
+}
+
+/// The sum of the timeouts of the registered callbacks. This is async-signal-safe.
+#[cfg(debug_assertions)]
+pub(crate) fn budget() -> Duration {
+    Duration::from_millis(BUDGET_MS.load(Ordering::Relaxed))
+}
+
+/// Run the registered callbacks. Called by the tracer thread.
+#[cfg(debug_assertions)]
+pub(crate) fn run(report: Report) {
+    let callbacks = std::mem::take(&mut *CALLBACKS.lock().unwrap_or_else(PoisonError::into_inner));
+    let report = std::sync::Arc::new(report);
+
+    for (timeout, callback) in callbacks {
+        let (sender, receiver) = mpsc::channel();
+        let report = report.clone();
+        let spawned = crate::handler::spawn_internal("batman-fatal", move || {
+            callback(&report);
+            let _ = sender.send(());
+        });
+
+        match spawned {
+            // A callback that panics drops the sender, which also ends the wait.
+            Ok(_) => {
+                if receiver.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout) {
+                    eprintln!("batman: a fatal callback did not finish within {timeout:?}");
+                }
+            }
+            Err(err) => eprintln!("batman: unable to run a fatal callback: {err}"),
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//! Typed access to the x86 floating point control and status registers.
+//!
+//! `batman` reads and writes these registers to enable exceptions and to report them. The same
+//! knowledge is useful for diagnostics, e.g. to print the floating point environment when a
+//! result looks wrong, or to check that a library did not change it.
+//!
+//! ```
+//! use batman::fenv::{FpEnv, Mxcsr};
+//!
+//! let env = FpEnv::save();
+//! if env.mxcsr.intersects(Mxcsr::FTZ | Mxcsr::DAZ) {
+//!     println!("subnormals are flushed: {:?}", env.mxcsr);
+//! }
+//! ```
+//!
+//! Unlike the rest of `batman`, this module also works when debug assertions are disabled. It is
+//! only available on `x86` and `x86_64`.
+
+use crate::x86_64::{fldcw, fnstcw, fnstsw, ldmxcsr, stmxcsr};
+use core::arch::asm;
+use std::fmt;
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not, Sub};
+
+/// Define a set of bits in a register, with a named constant for each bit.
+macro_rules! register {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident($ty:ty) {
+            $(
+                $(#[$flag_attr:meta])*
+                const $flag:ident = $bit:expr;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
+        pub struct $name($ty);
+
+        impl $name {
+            $(
+                $(#[$flag_attr])*
+                pub const $flag: Self = Self(1 << $bit);
+            )*
+
+            /// The names of the bits, for [`Debug`](fmt::Debug).
+            const NAMES: &'static [(&'static str, Self)] =
+                &[$((stringify!($flag), Self::$flag)),*];
+
+            /// No bits.
+            pub const fn empty() -> Self {
+                Self(0)
+            }
+
+            /// Create a value from the raw register contents. Reserved bits are retained.
+            pub const fn from_bits(bits: $ty) -> Self {
+                Self(bits)
+            }
+
+            /// The raw register contents.
+            pub const fn bits(self) -> $ty {
+                self.0
+            }
+
+            /// Returns `true` if no bits are set.
+            pub const fn is_empty(self) -> bool {
+                self.0 == 0
+            }
+
+            /// Returns `true` if all bits in `other` are set.
+            pub const fn contains(self, other: Self) -> bool {
+                self.0 & other.0 == other.0
+            }
+
+            /// Returns `true` if any bit in `other` is set.
+            pub const fn intersects(self, other: Self) -> bool {
+                self.0 & other.0 != 0
+            }
+
+            /// The bits that are set in either value.
+            pub const fn union(self, other: Self) -> Self {
+                Self(self.0 | other.0)
+            }
+
+            /// The bits that are set in both values.
+            pub const fn intersection(self, other: Self) -> Self {
+                Self(self.0 & other.0)
+            }
+
+            /// The bits that are set in `self` but not in `other`.
+            pub const fn difference(self, other: Self) -> Self {
+                Self(self.0 & !other.0)
+            }
+
+            /// Set the bits in `other`.
+            pub fn insert(&mut self, other: Self) {
+                self.0 |= other.0;
+            }
+
+            /// Clear the bits in `other`.
+            pub fn remove(&mut self, other: Self) {
+                self.0 &= !other.0;
+            }
+
+            /// Set or clear the bits in `other`.
+            pub fn set(&mut self, other: Self, value: bool) {
+                if value {
+                    self.insert(other);
+                } else {
+                    self.remove(other);
+                }
+            }
+        }
+
+        /// Formats the names of the bits that are set, e.g. `Mxcsr(ZE | IM | DM)`. Bits without a
+        /// name (like multi-bit fields) are formatted in hex.
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}(", stringify!($name))?;
+                let mut rest = *self;
+                let mut separator = "";
+                for &(name, flag) in Self::NAMES {
+                    if self.contains(flag) {
+                        write!(f, "{separator}{name}")?;
+                        rest.remove(flag);
+                        separator = " | ";
+                    }
+                }
+                if !rest.is_empty() || separator.is_empty() {
+                    write!(f, "{separator}{:#x}", rest.0)?;
+                }
+                write!(f, ")")
+            }
+        }
+
+        impl BitOr for $name {
+            type Output = Self;
+
+            fn bitor(self, rhs: Self) -> Self {
+                self.union(rhs)
+            }
+        }
+
+        impl BitOrAssign for $name {
+            fn bitor_assign(&mut self, rhs: Self) {
+                self.insert(rhs);
+            }
+        }
+
+        impl BitAnd for $name {
+            type Output = Self;
+
+            fn bitand(self, rhs: Self) -> Self {
+                self.intersection(rhs)
+            }
+        }
+
+        impl BitAndAssign for $name {
+            fn bitand_assign(&mut self, rhs: Self) {
+                self.0 &= rhs.0;
+            }
+        }
+
+        impl BitXor for $name {
+            type Output = Self;
+
+            fn bitxor(self, rhs: Self) -> Self {
+                Self(self.0 ^ rhs.0)
+            }
+        }
+
+        impl BitXorAssign for $name {
+            fn bitxor_assign(&mut self, rhs: Self) {
+                self.0 ^= rhs.0;
+            }
+        }
+
+        impl Sub for $name {
+            type Output = Self;
+
+            fn sub(self, rhs: Self) -> Self {
+                self.difference(rhs)
+            }
+        }
+
+        impl Not for $name {
+            type Output = Self;
+
+            fn not(self) -> Self {
+                Self(!self.0)
+            }
+        }
+    };
+}
+
+register! {
+    /// The SSE control and status register (MXCSR).
+    ///
+    /// The exception flags are sticky; they are set by SSE instructions and only cleared by
+    /// software. Each exception raises `SIGFPE` when its mask bit is clear.
+    pub struct Mxcsr(u32) {
+        /// Invalid Operation flag
+        const IE = 0;
+        /// Denormal Operand flag
+        const DE = 1;
+        /// Divide by Zero flag
+        const ZE = 2;
+        /// Overflow flag
+        const OE = 3;
+        /// Underflow flag
+        const UE = 4;
+        /// Precision (inexact result) flag
+        const PE = 5;
+        /// Denormals Are Zero
+        const DAZ = 6;
+        /// Invalid Operation mask
+        const IM = 7;
+        /// Denormal Operand mask
+        const DM = 8;
+        /// Divide by Zero mask
+        const ZM = 9;
+        /// Overflow mask
+        const OM = 10;
+        /// Underflow mask
+        const UM = 11;
+        /// Precision mask
+        const PM = 12;
+        /// Rounding Control, round down (toward negative infinity). With [`Mxcsr::RC_UP`], round
+        /// toward zero.
+        const RC_DOWN = 13;
+        /// Rounding Control, round up (toward positive infinity). With [`Mxcsr::RC_DOWN`], round
+        /// toward zero.
+        const RC_UP = 14;
+        /// Flush To Zero
+        const FTZ = 15;
+    }
+}
+
+impl Mxcsr {
+    /// All exception flags.
+    pub const FLAGS: Self = Self(0x3f);
+    /// All exception masks.
+    pub const MASKS: Self = Self(0x3f << 7);
+    /// The rounding control field.
+    pub const RC: Self = Self::RC_DOWN.union(Self::RC_UP);
+
+    /// Read MXCSR on the current thread.
+    ///
+    /// Returns [`Mxcsr::empty`] on processors without SSE.
+    pub fn read() -> Self {
+        Self(stmxcsr())
+    }
+
+    /// Write MXCSR on the current thread.
+    ///
+    /// Does nothing on processors without SSE.
+    ///
+    /// # Safety
+    ///
+    /// Unmasking an exception raises `SIGFPE` at the next SSE instruction that raises it, and
+    /// changing the rounding or denormal modes changes the results of other code on the thread.
+    /// Setting a reserved bit raises a general protection fault.
+    pub unsafe fn write(self) {
+        ldmxcsr(self.0);
+    }
+}
+
+register! {
+    /// The x87 control word (FCW).
+    pub struct X87Control(u16) {
+        /// Invalid Operation mask
+        const IM = 0;
+        /// Denormal Operand mask
+        const DM = 1;
+        /// Divide by Zero mask
+        const ZM = 2;
+        /// Overflow mask
+        const OM = 3;
+        /// Underflow mask
+        const UM = 4;
+        /// Precision mask
+        const PM = 5;
+        /// Precision Control, low bit. Both bits are set for 64-bit significands (the default),
+        /// and only [`X87Control::PC1`] is set for 53-bit significands.
+        const PC0 = 8;
+        /// Precision Control, high bit
+        const PC1 = 9;
+        /// Rounding Control, round down (toward negative infinity). With [`X87Control::RC_UP`],
+        /// round toward zero.
+        const RC_DOWN = 10;
+        /// Rounding Control, round up (toward positive infinity). With [`X87Control::RC_DOWN`],
+        /// round toward zero.
+        const RC_UP = 11;
+        /// Infinity Control, which has no effect since the 80387
+        const IC = 12;
+    }
+}
+
+impl X87Control {
+    /// All exception masks.
+    pub const MASKS: Self = Self(0x3f);
+    /// The precision control field.
+    pub const PC: Self = Self::PC0.union(Self::PC1);
+    /// The rounding control field.
+    pub const RC: Self = Self::RC_DOWN.union(Self::RC_UP);
+
+    /// Read the x87 control word on the current thread.
+    pub fn read() -> Self {
+        Self(fnstcw())
+    }
+
+    /// Write the x87 control word on the current thread.
+    ///
+    /// # Safety
+    ///
+    /// Unmasking an exception whose flag is set in [`X87Status`] raises `SIGFPE` at the next x87
+    /// instruction. Changing the precision or rounding control changes the results of other code
+    /// on the thread.
+    pub unsafe fn write(self) {
+        fldcw(self.0);
+    }
+}
+
+register! {
+    /// The x87 status word (FSW).
+    ///
+    /// The status word cannot be written directly. [`X87Status::clear`] clears the exception
+    /// flags, and [`FpEnv::restore`] restores them.
+    pub struct X87Status(u16) {
+        /// Invalid Operation flag
+        const IE = 0;
+        /// Denormal Operand flag
+        const DE = 1;
+        /// Divide by Zero flag
+        const ZE = 2;
+        /// Overflow flag
+        const OE = 3;
+        /// Underflow flag
+        const UE = 4;
+        /// Precision (inexact result) flag
+        const PE = 5;
+        /// Stack Fault, set with [`X87Status::IE`] on register stack overflow or underflow
+        const SF = 6;
+        /// Error Summary, set when an unmasked exception is pending
+        const ES = 7;
+        /// Condition Code 0
+        const C0 = 8;
+        /// Condition Code 1
+        const C1 = 9;
+        /// Condition Code 2
+        const C2 = 10;
+        /// Condition Code 3
+        const C3 = 14;
+        /// Busy, a copy of [`X87Status::ES`]
+        const B = 15;
+    }
+}
+
+impl X87Status {
+    /// All exception flags.
+    pub const FLAGS: Self = Self(0x3f);
+    /// The bits that are cleared by `fclex`.
+    const CLEAR: Self = Self(0x80ff);
+    /// Offset of the top of stack pointer
+    const TOP_SHIFT: u16 = 11;
+
+    /// Read the x87 status word on the current thread.
+    pub fn read() -> Self {
+        Self(fnstsw())
+    }
+
+    /// Clear the exception flags (and the stack fault, error summary, and busy bits) on the
+    /// current thread. This also discards a pending exception.
+    pub fn clear() {
+        // SAFETY: Clearing the flags cannot raise an exception.
+        unsafe { asm!("fnclex", options(nomem, nostack, preserves_flags)) };
+    }
+
+    /// The index of the register at the top of the x87 register stack.
+    pub const fn top(self) -> u8 {
+        (self.0 >> Self::TOP_SHIFT) as u8 & 0x7
+    }
+}
+
+/// A snapshot of the floating point environment of a thread.
+///
+/// Snapshots can be compared to find code that changes the environment:
+///
+/// ```
+/// use batman::fenv::{FpEnv, Mxcsr};
+///
+/// let before = FpEnv::save();
+/// // ... call into a library ...
+/// let after = FpEnv::save();
+/// assert_eq!(before.mxcsr - Mxcsr::FLAGS, after.mxcsr - Mxcsr::FLAGS);
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FpEnv {
+    /// The SSE control and status register.
+    pub mxcsr: Mxcsr,
+    /// The x87 control word.
+    pub control: X87Control,
+    /// The x87 status word.
+    pub status: X87Status,
+}
+
+impl FpEnv {
+    /// Save the floating point environment of the current thread.
+    pub fn save() -> Self {
+        Self {
+            mxcsr: Mxcsr::read(),
+            control: X87Control::read(),
+            status: X87Status::read(),
+        }
+    }
+
+    /// Restore a saved floating point environment on the current thread.
+    ///
+    /// MXCSR, the x87 control word, and the x87 exception flags are restored. The x87 condition
+    /// codes and register stack are left alone.
+    ///
+    /// # Safety
+    ///
+    /// See [`Mxcsr::write`] and [`X87Control::write`]. Restoring an x87 exception flag that is
+    /// unmasked raises `SIGFPE` at the next x87 instruction.
+    pub unsafe fn restore(&self) {
+        self.mxcsr.write();
+
+        // The status word can only be written by loading the whole x87 environment. The 28-byte
+        // protected mode format has the control word at offset 0 and the status word at offset
+        // 4. `fnstenv` masks all exceptions, so the environment is loaded with `fldenv` before
+        // any other x87 instruction runs.
+        let mut env = [0_u16; 14];
+        asm!("fnstenv [{}]", in(reg) env.as_mut_ptr(), options(nostack, preserves_flags));
+        env[0] = self.control.0;
+        let status = X87Status(env[2]).difference(X87Status::CLEAR);
+        env[2] = status.union(self.status & X87Status::CLEAR).0;
+        asm!("fldenv [{}]", in(reg) env.as_ptr(), options(nostack, preserves_flags));
+    }
+}
@@ -0,0 +1,94 @@
+//! A backtrace that follows the chain of frame pointers, as an alternative to `libunwind`.
+//!
+//! `libunwind` parses unwind tables to step through frames, and it may allocate and take locks
+//! while doing so, which is not allowed in a signal handler. When every frame saves the frame
+//! pointer of its caller (e.g. built with `-C force-frame-pointers=yes`), the backtrace is a
+//! linked list on the stack that can be followed with plain memory reads. It starts at the
+//! faulting instruction in the signal context instead of in the signal handler.
+//!
+//! Each frame pointer is checked against the part of the thread's stack that is in use: above the
+//! interrupted stack pointer, and below the top of the stack that was recorded when the thread was
+//! armed. In a frame without a frame pointer, the register holds an arbitrary value. If it happens
+//! to point into that part of the stack, the walk reads stale values and prints bogus frames, but
+//! it only reads mapped memory. Otherwise the backtrace ends there.
+
+use std::cell::Cell;
+use std::{mem, ptr};
+
+use crate::x86_64;
+
+const WORD: usize = mem::size_of::<usize>();
+
+thread_local! {
+    // The lowest and highest addresses of the thread's stack, or zero when they are not known.
+    // This is read by the signal handler, so it must be const-initialized.
+    static STACK: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+/// Record the bounds of the current thread's stack. This is not async-signal-safe, so threads
+/// that are armed in a signal handler fall back to `libunwind`.
+pub(crate) fn record_stack() {
+    // SAFETY: `attr` is initialized by `pthread_getattr_np` before it is used, and destroyed
+    // afterward.
+    unsafe {
+        let mut attr = mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return;
+        }
+        let mut low = ptr::null_mut();
+        let mut size = 0;
+        let result = libc::pthread_attr_getstack(&attr, &mut low, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+
+        if result == 0 {
+            STACK.set((low as usize, low as usize + size));
+        }
+    }
+}
+
+/// Write the address of the faulting instruction and the return address of each frame to
+/// `addresses`. Returns the number of addresses, or `None` if the stack bounds are not known.
+///
+/// This is async-signal-safe. It does not allocate or take locks.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler, which
+/// interrupted the current thread.
+pub(crate) unsafe fn trace(
+    context: *const libc::ucontext_t,
+    addresses: &mut [usize],
+) -> Option<usize> {
+    let (low, high) = STACK.get();
+    if low == high || addresses.is_empty() {
+        return None;
+    }
+
+    addresses[0] = x86_64::ip_in_context(context);
+    let mut len = 1;
+
+    // Frames are above the interrupted stack pointer. The stack below it may not be mapped; the
+    // recorded lower bound of the main thread's stack is the resource limit, not the mapping.
+    let low = low.max(x86_64::sp_in_context(context));
+
+    // Each frame pointer points at the caller's frame pointer, followed by the return address.
+    let mut fp = x86_64::fp_in_context(context);
+    while len < addresses.len() && fp >= low && fp <= high - 2 * WORD && fp.is_multiple_of(WORD) {
+        let next = ptr::read(fp as *const usize);
+        let ip = ptr::read((fp + WORD) as *const usize);
+        if ip == 0 {
+            break;
+        }
+        addresses[len] = ip;
+        len += 1;
+
+        // The stack grows down, so the caller's frame is at a higher address. This also ends a
+        // chain that loops.
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+
+    Some(len)
+}
@@ -0,0 +1,813 @@
+//! The `SIGFPE` signal handler and the tracer thread that prints backtraces on its behalf.
+
+use crate::beacon::Beacon;
+use crate::{fatal, stack};
+#[cfg(target_os = "linux")]
+use crate::helper;
+use array_macro::array;
+use backtrace::Frame;
+use log::{debug, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+use std::sync::atomic::AtomicU8;
+#[cfg(unix)]
+use std::sync::atomic::AtomicUsize;
+#[cfg(target_os = "linux")]
+use std::sync::mpsc;
+#[cfg(unix)]
+use std::sync::OnceLock;
+use std::sync::{Mutex, PoisonError};
+use std::{cell::SyncUnsafeCell, thread};
+use std::time::Duration;
+use std::{hint::unreachable_unchecked, io};
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+use crate::{backend, policy, resume, suppress, x86_64, Action};
+#[cfg(all(
+    feature = "frame-pointers",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+use crate::frame_pointers;
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+use std::{cell::Cell, ffi::c_void, mem, ptr};
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse2"
+))]
+use crate::simd;
+
+#[cfg(windows)]
+use windows_sys::Win32::System::{Diagnostics::Debug::RaiseFailFastException, Threading};
+
+// 200 frames is less than 64 KiB on Windows x86_64, and about 6 KiB on Linux/macOS.
+pub(crate) const MAX_FRAMES: usize = 200;
+
+// Backtrace frames are statically allocated because heap allocations are not safe within
+// signal handlers.
+// See: https://www.man7.org/linux/man-pages/man7/signal-safety.7.html)
+//
+// # SAFETY:
+//
+// `Frame` implements `Send` and `Sync`. `SyncUnsafeCell` is used for interior mutability.
+// All reads and writes are synchronized through the `FRAMES_AVAILABLE` and `FRAMES_HANDLED`
+// beacons.
+static FRAMES: [SyncUnsafeCell<Option<Frame>>; MAX_FRAMES] =
+    array![_ => SyncUnsafeCell::new(None); MAX_FRAMES];
+
+// The addresses captured by following frame pointers, and how many there are. When the count is
+// zero, the backtrace is in `FRAMES` instead. Synchronized like `FRAMES`.
+#[cfg(all(
+    feature = "frame-pointers",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+static ADDRESSES: SyncUnsafeCell<[usize; MAX_FRAMES]> = SyncUnsafeCell::new([0; MAX_FRAMES]);
+#[cfg(all(
+    feature = "frame-pointers",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+static ADDRESS_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// The floating point registers of the thread that raised `SIGFPE`, synchronized like `FRAMES`.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+static REGISTERS: SyncUnsafeCell<Option<x86_64::Registers>> = SyncUnsafeCell::new(None);
+
+// The exceptions that the thread that raised `SIGFPE` raised, for the fatal callbacks.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+static EXCEPTIONS: AtomicU8 = AtomicU8::new(0);
+
+// These beacons are used to communicate between the signal handler and the thread that prints
+// the backtrace.
+static FRAMES_AVAILABLE: Beacon = Beacon::new();
+static FRAMES_HANDLED: Beacon = Beacon::new();
+
+// Set by the signal handler when the helper process printed the report.
+#[cfg(target_os = "linux")]
+static REPORTED_BY_HELPER: AtomicBool = AtomicBool::new(false);
+
+// Set when `install_capture` did not install the capture, because `SIGFPE` already had a handler.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+static CAPTURE_SKIPPED: AtomicBool = AtomicBool::new(false);
+
+// This atomic makes the signal handler reentrant.
+static HANDLING: AtomicBool = AtomicBool::new(false);
+
+// The tracer thread and signal handler are process-wide, so they are only installed once. The
+// lock is held for the duration of the installation so that racing threads do not both install.
+static INSTALLED: Mutex<bool> = Mutex::new(false);
+
+// The `SIGFPE` handler address that `signal-hook-registry` installed, checked by
+// `installation_problems`.
+#[cfg(unix)]
+static DISPOSITION: AtomicUsize = AtomicUsize::new(0);
+
+// The `SIGFPE` disposition before `install`. Signals that are not floating point exceptions are
+// forwarded to it.
+#[cfg(unix)]
+static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+// `si_code` values for integer division by zero and integer overflow. These are raised by
+// integer instructions (e.g. `div` on x86), which `batman` does not configure.
+#[cfg(all(unix, not(target_vendor = "apple")))]
+const FPE_INTEGER: [libc::c_int; 2] = [1, 2];
+#[cfg(target_vendor = "apple")]
+const FPE_INTEGER: [libc::c_int; 2] = [7, 8];
+
+thread_local! {
+    // Set by `raise` so that the handler knows that the signal was raised by `batman` instead of
+    // a trap. This is read by the signal handler, so it must be const-initialized.
+    static RAISED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+// The `ucontext_t` of the thread that raised `SIGFPE`. This is captured by a handler that
+// `signal-hook-registry` chains before its own actions, because the actions do not receive it.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+thread_local! {
+    static CONTEXT: Cell<*mut libc::ucontext_t> = const { Cell::new(ptr::null_mut()) };
+}
+
+// The kernel thread IDs of `batman`'s own threads: the tracer, the sampler, and the threads that
+// run fatal callbacks. They must never have exceptions enabled by `signal_all_threads`.
+#[cfg(target_os = "linux")]
+static INTERNAL_TIDS: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+
+/// Spawn one of `batman`'s own threads. On Linux, its thread ID is registered before this
+/// returns, so that [`crate::signal_all_threads`] and the sampler leave it out, and it is
+/// unregistered when `f` returns.
+pub(crate) fn spawn_internal<F>(name: &str, f: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    #[cfg(target_os = "linux")]
+    {
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new().name(name.into()).spawn(move || {
+            let _internal = Internal::register();
+            let _ = sender.send(());
+            f();
+        })?;
+
+        // The thread may have failed to start, in which case there is nothing to wait for.
+        let _ = receiver.recv();
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    thread::Builder::new().name(name.into()).spawn(f)?;
+
+    Ok(())
+}
+
+/// The thread IDs of `batman`'s own threads that are running.
+#[cfg(target_os = "linux")]
+pub(crate) fn internal_threads() -> Vec<libc::pid_t> {
+    INTERNAL_TIDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// Keeps the current thread in [`INTERNAL_TIDS`] until it is dropped.
+#[cfg(target_os = "linux")]
+struct Internal(libc::pid_t);
+
+#[cfg(target_os = "linux")]
+impl Internal {
+    fn register() -> Self {
+        // SAFETY: `gettid` has no preconditions.
+        let tid = unsafe { libc::gettid() };
+        INTERNAL_TIDS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(tid);
+
+        Self(tid)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Internal {
+    fn drop(&mut self) {
+        INTERNAL_TIDS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|&tid| tid != self.0);
+    }
+}
+
+/// Install the process-wide `SIGFPE` handler and spawn the tracer thread.
+///
+/// This is idempotent; only the first successful call has any effect.
+///
+/// # Safety
+///
+/// See [`crate::signal`].
+pub(crate) unsafe fn install() -> io::Result<()> {
+    let mut installed = INSTALLED.lock().unwrap_or_else(PoisonError::into_inner);
+    if *installed {
+        return Ok(());
+    }
+
+    debug!("Installing SIGFPE handler");
+
+    // The signal handlers use the backends, but selecting them is not async-signal-safe.
+    crate::backend::active();
+
+    #[cfg(unix)]
+    {
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGFPE, std::ptr::null(), &mut previous) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let _ = PREVIOUS.set(previous);
+    }
+
+    // Spawn a thread (called "tracer") that can use the standard library. The tracer prints the
+    // backtrace that it receives from the signal handler.
+    //
+    // AArch64 macOS apparently raises SIGILL instead of SIGFPE for floating point
+    // exceptions, so this is a case we will need to handle appropriately.
+    FRAMES_AVAILABLE.init()?;
+    FRAMES_HANDLED.init()?;
+
+    spawn_internal("batman-tracer", move || {
+        // We're not running in the signal handler, so we can do anything!
+        // However, we do need to ensure we are synchronized with the signal handler.
+        // Wait for the signal handler to send the beacon when frames are available.
+        FRAMES_AVAILABLE.wait();
+
+        // Note that it is possible for the tracer thread to deadlock. E.g., if the signalling
+        // thread is holding the stdout or stderr locks. That's OK, because the signal handler
+        // will always terminate the process, even if we don't send a beacon back in a timely
+        // manner.
+        debug!("Received beacon, processing backtrace...");
+
+        // Sanity check: If this beacon is set, we're DOA.
+        assert!(!FRAMES_HANDLED.is_sent());
+
+        // The helper process already printed the report.
+        #[cfg(target_os = "linux")]
+        let print = !REPORTED_BY_HELPER.load(Ordering::Acquire);
+        #[cfg(not(target_os = "linux"))]
+        let print = true;
+
+        // Show a pretty backtrace.
+        if print {
+            // SAFETY: See `FRAMES`.
+            #[cfg(all(
+                feature = "frame-pointers",
+                target_os = "linux",
+                any(target_arch = "x86", target_arch = "x86_64")
+            ))]
+            let addresses = unsafe {
+                let len = ADDRESS_COUNT.load(Ordering::Acquire);
+                &(&*ADDRESSES.get())[..len]
+            };
+            #[cfg(not(all(
+                feature = "frame-pointers",
+                target_os = "linux",
+                any(target_arch = "x86", target_arch = "x86_64")
+            )))]
+            let addresses: &[usize] = &[];
+
+            // The frame pointers were followed from the faulting instruction.
+            #[cfg(all(
+                feature = "frame-pointers",
+                target_os = "linux",
+                any(target_arch = "x86", target_arch = "x86_64")
+            ))]
+            if !addresses.is_empty() {
+                stack::print_addresses(addresses.to_vec(), false, &Some);
+            }
+
+            if addresses.is_empty() {
+                let mut frames = vec![];
+                for frame in FRAMES.iter() {
+                    // SAFETY: This is the only thread accessing `FRAMES`, guaranteed by the
+                    // atomic beacons and signal handler reentrancy guarantees.
+                    match unsafe { &frame.get().read() } {
+                        Some(frame) => frames.push(frame.clone()),
+                        None => break,
+                    }
+                }
+                stack::print(frames);
+            }
+
+            // SAFETY: See `FRAMES`.
+            #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+            if let Some(registers) = unsafe { REGISTERS.get().read() } {
+                print_registers(&registers, unsafe { libc::getpid() }, &Some);
+            }
+        }
+
+        // Give the application a chance to save its work.
+        #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+        let exceptions = policy::Exceptions::from_bits(EXCEPTIONS.load(Ordering::Relaxed))
+            .iter()
+            .collect();
+        #[cfg(not(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64"))))]
+        let exceptions = vec![];
+        fatal::run(fatal::Report::new(exceptions));
+
+        debug!("Sending beacon and stopping thread...");
+
+        // Send a beacon back to the signal handler.
+        FRAMES_HANDLED.send();
+
+        // All done! We can safely exit the tracer thread now.
+    })?;
+
+    // SAFETY: This is a signal handler. It must be written with extreme care. The primary
+    // concerns from a POSIX point of view is that signal handlers are not allowed to touch
+    // global state unless it is done through synchronization with atomics, the number of
+    // libc/syscalls allowed is very limited, and reentrancy must be handled properly.
+    //
+    // Additionally, SIGFPE is an unrecoverable signal. This signal handler is not allowed to
+    // return normally, or send any signal or call any function that would cause the signalling
+    // thread to resume. The exceptions are suppressed exceptions and exceptions configured to
+    // continue with `set_action`, which resume the thread with exceptions masked for the faulting
+    // instruction (see the `resume` module).
+    //
+    // Panics within a signal handler are forbidden. This signal handler terminates the process
+    // in a way that user code is not able to catch, i.e., unconditionally. This serves as both
+    // a way to terminate the process where a panic would normally be used, and also as the
+    // normal exit behavior for the signal handler. In other words, once invoked, this signal
+    // handler guarantees that the process will terminate in a finite time.
+    //
+    // See: https://www.man7.org/linux/man-pages/man7/signal-safety.7.html)
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    install_capture()?;
+
+    #[cfg(not(windows))]
+    signal_hook_registry::register_unchecked(libc::SIGFPE, move |info| {
+        debug_assert!(crate::backend::is_selected());
+
+        #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+        let context = CONTEXT.replace(ptr::null_mut());
+
+        #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+        let foreign = is_foreign(info, context);
+        #[cfg(not(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64"))))]
+        let foreign = is_foreign(info);
+        if foreign {
+            forward(info);
+            return;
+        }
+
+        #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+        if !context.is_null() {
+            let address = x86_64::fault_address_in_context(context);
+            let raised = backend::raised_in_context(context);
+            let action = if suppress::is_suppressed(address) {
+                Action::Ignore
+            } else {
+                policy::handle(raised, address)
+            };
+
+            let resumed = match action {
+                Action::Abort => false,
+                Action::Panic => raised
+                    .iter()
+                    .find(|&exception| policy::action(exception) == Action::Panic)
+                    .is_some_and(|exception| resume::panic(context, exception)),
+                _ => resume::resume(context),
+            };
+            if resumed {
+                return;
+            }
+        }
+
+        #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+        report(context);
+
+        #[cfg(not(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64"))))]
+        report();
+    })?;
+
+    #[cfg(windows)]
+    signal_hook_registry::register_signal_unchecked(libc::SIGFPE, || report())?;
+
+    #[cfg(unix)]
+    run_on_altstack()?;
+
+    #[cfg(unix)]
+    DISPOSITION.store(current_disposition()?, Ordering::Relaxed);
+
+    *installed = true;
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    if let Some(path) = std::env::var_os(suppress::ENV_VAR) {
+        suppress::suppress_file(path.as_ref())?;
+    }
+
+    Ok(())
+}
+
+/// Raise `SIGFPE` on the current thread to report an exception (or another problem) that was
+/// detected without a trap. The handler reports it like a trapped exception, and terminates the
+/// process.
+pub(crate) fn raise() {
+    RAISED.set(true);
+    // SAFETY: `raise` is async-signal-safe.
+    unsafe { libc::raise(libc::SIGFPE) };
+}
+
+/// Returns `true` if the signal is not a floating point exception that `batman` configured, e.g.
+/// integer division by zero in C code, or an exception that was trapped by other code on a thread
+/// that `batman` did not arm.
+///
+/// Threads inherit the floating point configuration from the thread that spawned them, so a thread
+/// that was not armed (e.g. spawned with `std::thread::spawn` by an armed thread) may still trap
+/// with the configuration that `batman` enables. It is decided from the signal context: the
+/// exception is reported if the trapped exceptions are enabled by the policy, and the thread has
+/// the masks that `batman` sets. Without a context, it is always reported.
+///
+/// # Safety
+///
+/// `context` must be the captured `ucontext_t`, or null.
+#[cfg(unix)]
+unsafe fn is_foreign(
+    info: &libc::siginfo_t,
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    context: *mut libc::ucontext_t,
+) -> bool {
+    if RAISED.replace(false) {
+        return false;
+    }
+    if FPE_INTEGER.contains(&info.si_code) {
+        return true;
+    }
+    if crate::ARMED.get() {
+        return false;
+    }
+
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    if !context.is_null() {
+        let enabled = policy::enabled();
+        let raised = backend::raised_in_context(context);
+        return raised.bits() & !enabled.bits() != 0
+            || !backend::is_enabled_in_context(context, enabled);
+    }
+
+    false
+}
+
+/// Forward a signal that is not a floating point exception to the previous disposition.
+///
+/// `signal-hook-registry` has already called the previous handler, if there is one. Otherwise,
+/// the default action is performed, which terminates the process.
+///
+/// # Safety
+///
+/// Must only be called by the `SIGFPE` signal handler.
+#[cfg(unix)]
+unsafe fn forward(info: &libc::siginfo_t) {
+    let Some(previous) = PREVIOUS.get() else {
+        return;
+    };
+
+    match previous.sa_sigaction {
+        // A trap cannot be ignored; the faulting instruction would be executed again forever.
+        libc::SIG_IGN if info.si_code <= 0 => (),
+        libc::SIG_DFL | libc::SIG_IGN => {
+            // Restore the default disposition and raise the signal again. It is delivered as soon
+            // as this handler returns and unblocks it.
+            let mut default: libc::sigaction = std::mem::zeroed();
+            default.sa_sigaction = libc::SIG_DFL;
+            libc::sigaction(libc::SIGFPE, &default, std::ptr::null_mut());
+            libc::raise(libc::SIGFPE);
+        }
+        _ => (),
+    }
+}
+
+/// Describe the ways that the process-wide `SIGFPE` handling was changed since it was installed.
+///
+/// The thread's signal mask is checked too, because a blocked `SIGFPE` cannot be handled.
+pub(crate) fn installation_problems() -> io::Result<Vec<String>> {
+    let mut problems = vec![];
+    if !*INSTALLED.lock().unwrap_or_else(PoisonError::into_inner) {
+        problems.push("the SIGFPE handler is not installed".to_string());
+        return Ok(problems);
+    }
+
+    #[cfg(unix)]
+    // SAFETY: These only read the signal mask and the disposition.
+    unsafe {
+        let mut mask: libc::sigset_t = std::mem::zeroed();
+        let result = libc::pthread_sigmask(libc::SIG_BLOCK, std::ptr::null(), &mut mask);
+        if result != 0 {
+            return Err(io::Error::from_raw_os_error(result));
+        }
+        if libc::sigismember(&mask, libc::SIGFPE) == 1 {
+            problems.push("SIGFPE is blocked in the signal mask of this thread".to_string());
+        }
+
+        let expected = DISPOSITION.load(Ordering::Relaxed);
+        let current = current_disposition()?;
+        if current != expected {
+            let current = match current {
+                libc::SIG_DFL => "the default action".to_string(),
+                libc::SIG_IGN => "SIG_IGN".to_string(),
+                handler => format!("a handler at {handler:#x}"),
+            };
+            problems.push(format!(
+                "the SIGFPE handler at {expected:#x} was replaced by {current}"
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+/// Get the process-wide `SIGFPE` handler address (or `SIG_DFL` or `SIG_IGN`).
+#[cfg(unix)]
+fn current_disposition() -> io::Result<libc::sighandler_t> {
+    // SAFETY: This only reads the disposition.
+    unsafe {
+        let mut current: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(libc::SIGFPE, std::ptr::null(), &mut current) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(current.sa_sigaction)
+    }
+}
+
+/// Capture a backtrace and the floating point registers, send them to the tracer thread, and
+/// terminate the process.
+///
+/// # Safety
+///
+/// Must only be called by the `SIGFPE` signal handler, or by the `SIGTRAP` handler that steps a
+/// thread after a floating point exception. `context` must be the captured `ucontext_t`, or null.
+pub(crate) unsafe fn report(
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    context: *mut libc::ucontext_t,
+) -> ! {
+    let exch = HANDLING.compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire);
+    if exch.is_err() {
+        // The signal handler is already running and another thread has raised the signal.
+        // This handler is made reentrant by pausing the new thread forever. The process
+        // always terminates anyway.
+
+        #[cfg(unix)]
+        loop {
+            libc::pause();
+        }
+
+        #[cfg(windows)]
+        loop {
+            Threading::Sleep(Threading::INFINITE);
+        }
+
+        #[cfg(not(any(unix, windows)))]
+        libc::raise(libc::SIGKILL);
+    }
+
+    // Follow the frame pointers from the faulting instruction, if they are enabled and the stack
+    // bounds of this thread are known. This does not allocate or take locks.
+    #[cfg(all(
+        feature = "frame-pointers",
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64")
+    ))]
+    if !context.is_null() {
+        // SAFETY: This is the only thread writing `ADDRESSES`, like `FRAMES`.
+        if let Some(len) = frame_pointers::trace(context, &mut *ADDRESSES.get()) {
+            ADDRESS_COUNT.store(len, Ordering::Release);
+        }
+    }
+    #[cfg(all(
+        feature = "frame-pointers",
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64")
+    ))]
+    let traced = ADDRESS_COUNT.load(Ordering::Acquire) > 0;
+    #[cfg(not(all(
+        feature = "frame-pointers",
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64")
+    )))]
+    let traced = false;
+
+    let mut i = 0;
+    // SAFETY: We are certain that this is the only thread that gets here because of the
+    // `HANDLING` atomic.
+    //
+    // Note that we cannot use `std::backtrace` because it allocates on the heap and uses
+    // OS primitive locks (which are explicitly forbidden in signal handlers by POSIX).
+    // TODO: Make sure the `backtrace::trace_unsynchronized` does not allocate on the heap.
+    // The `frame-pointers` feature avoids it, because libunwind is not called at all when the
+    // frame pointers were followed.
+    if !traced {
+        backtrace::trace_unsynchronized(|frame| {
+            // Cap the number of frames captured to fit in the static allocation.
+            if i >= MAX_FRAMES {
+                return false;
+            }
+
+            // Insert the frame into the statically-allocated buffer.
+            //
+            // SAFETY: `i` is guaranteed in-bounds and there are no other readers or writers.
+            // Note that the `Index` implementation for `slice` has a conditional panic, but
+            // the bounds check ensures that the panic is not possible. It is always safe to
+            // drop the initial `None` values at each array index.
+            std::ptr::replace(FRAMES[i].get(), Some(frame.clone()));
+
+            i += 1;
+
+            true
+        });
+    }
+
+    // SAFETY: This is the only thread writing `REGISTERS`, like `FRAMES`.
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    if !context.is_null() {
+        REGISTERS.get().write(x86_64::registers_in_context(context));
+        EXCEPTIONS.store(backend::raised_in_context(context).bits(), Ordering::Relaxed);
+    }
+
+    // Send the frames to the helper process, if there is one. It prints the report without
+    // taking any locks in this process.
+    #[cfg(target_os = "linux")]
+    {
+        let addresses = FRAMES[..i]
+            .iter()
+            .filter_map(|frame| (*frame.get()).as_ref().map(|frame| frame.ip() as usize));
+        #[cfg(all(
+            feature = "frame-pointers",
+            any(target_arch = "x86", target_arch = "x86_64")
+        ))]
+        let addresses = addresses.chain(
+            (&*ADDRESSES.get())[..ADDRESS_COUNT.load(Ordering::Acquire)]
+                .iter()
+                .copied(),
+        );
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let reported = helper::send(addresses, !traced, REGISTERS.get().read());
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        let reported = helper::send(addresses, !traced);
+        REPORTED_BY_HELPER.store(reported, Ordering::Release);
+    }
+
+    // Send a beacon to alert the tracer thread that the frames are ready to be consumed.
+    if FRAMES_AVAILABLE.is_sent() {
+        // Sanity check: If this beacon is set, we're DOA.
+        fatal();
+    }
+    FRAMES_AVAILABLE.send();
+
+    // Wait for the tracer thread to print the backtrace and run the fatal callbacks. Timeout
+    // occurs after 3 seconds, plus the callback timeouts.
+    FRAMES_HANDLED.wait_timeout(Duration::from_secs(3).saturating_add(fatal::budget()));
+
+    fatal();
+}
+
+/// Print the floating point registers of the thread that raised `SIGFPE`, and what they say about
+/// the faulting instruction. `pid` is the process that raised it, and `translate` maps its
+/// instruction addresses to this process.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+pub(crate) fn print_registers(
+    registers: &x86_64::Registers,
+    pid: libc::pid_t,
+    translate: &dyn Fn(usize) -> Option<usize>,
+) {
+    if let Some((address, opcode)) = registers.x87_fault() {
+        stack::print_x87(address, opcode, translate);
+    }
+    #[cfg(target_feature = "sse2")]
+    if let Some(report) = simd::describe(registers, pid) {
+        eprint!("\n{report}");
+    }
+    #[cfg(not(target_feature = "sse2"))]
+    let _ = pid;
+    eprintln!("\nRegisters:\n{registers}");
+}
+
+// I've seen things you people wouldn't believe.
+// Attack ships on fire off the shoulder of Orion.
+// I watched C-beams glitter in the dark near the Tannhauser gate.
+// All those moments will be lost in time.
+// Like tears in rain.
+// Time to die...
+unsafe fn fatal() -> ! {
+    #[cfg(not(windows))]
+    libc::raise(libc::SIGKILL);
+
+    #[cfg(windows)]
+    RaiseFailFastException(std::ptr::null(), std::ptr::null(), 0);
+
+    unreachable_unchecked();
+}
+
+/// Install a `SIGFPE` handler that captures the `ucontext_t` for the `signal-hook-registry`
+/// actions.
+///
+/// This must be called before the first action is registered. `signal-hook-registry` replaces
+/// the handler, and calls it as the previous handler before running its actions. The capture is
+/// skipped if `SIGFPE` already has a handler, in which case suppressions are not available (see
+/// [`check_capture`]).
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+unsafe fn install_capture() -> io::Result<()> {
+    extern "C" fn capture(_signal: libc::c_int, _info: *mut libc::siginfo_t, context: *mut c_void) {
+        CONTEXT.set(context.cast());
+    }
+
+    let mut old: libc::sigaction = mem::zeroed();
+    if libc::sigaction(libc::SIGFPE, ptr::null(), &mut old) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if old.sa_sigaction != libc::SIG_DFL {
+        CAPTURE_SKIPPED.store(true, Ordering::Relaxed);
+        warn!(
+            "SIGFPE already has a handler; suppressions and actions other than `Abort` and \
+            `Ignore` are not available, and exceptions will abort"
+        );
+        return Ok(());
+    }
+
+    let mut new: libc::sigaction = mem::zeroed();
+    new.sa_sigaction = capture as *const () as usize;
+    new.sa_flags = libc::SA_SIGINFO;
+    if libc::sigaction(libc::SIGFPE, &new, ptr::null_mut()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Returns an error if the signal context is not captured, because `SIGFPE` already had a
+/// handler when [`install`] was called. Suppressions and actions that resume the thread need the
+/// context; without it, the exceptions abort.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+pub(crate) fn check_capture() -> io::Result<()> {
+    match CAPTURE_SKIPPED.load(Ordering::Relaxed) {
+        true => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SIGFPE already had a handler when `batman::signal()` was called",
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Run the `SIGFPE` handler on the alternate signal stack of the thread, which is installed when
+/// the thread is armed. `signal-hook-registry` does not set `SA_ONSTACK`, so it is added to the
+/// registered handler.
+#[cfg(unix)]
+unsafe fn run_on_altstack() -> io::Result<()> {
+    let mut action: libc::sigaction = std::mem::zeroed();
+    if libc::sigaction(libc::SIGFPE, std::ptr::null(), &mut action) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    action.sa_flags |= libc::SA_ONSTACK;
+    if libc::sigaction(libc::SIGFPE, &action, std::ptr::null_mut()) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+// Serializes `claim_realtime_signal`, so that two callers do not claim the same signal.
+#[cfg(target_os = "linux")]
+static CLAIM: Mutex<()> = Mutex::new(());
+
+/// Install `action` on the first real-time signal that nobody else is using, and return the
+/// signal number.
+///
+/// # Safety
+///
+/// `action` must be async-signal-safe.
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn claim_realtime_signal(
+    action: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut std::ffi::c_void),
+) -> io::Result<libc::c_int> {
+    let _guard = CLAIM.lock().unwrap_or_else(PoisonError::into_inner);
+
+    // See `install`.
+    crate::backend::active();
+
+    for signal in libc::SIGRTMIN()..=libc::SIGRTMAX() {
+        let mut old: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(signal, std::ptr::null(), &mut old) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if old.sa_sigaction != libc::SIG_DFL {
+            continue;
+        }
+
+        let mut new: libc::sigaction = std::mem::zeroed();
+        new.sa_sigaction = action as *const () as usize;
+        new.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        if libc::sigaction(signal, &new, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        return Ok(signal);
+    }
+
+    Err(io::Error::other("no real-time signal is available"))
+}
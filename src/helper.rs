@@ -0,0 +1,287 @@
+//! A helper process that prints the crash report on behalf of the process that raised the
+//! exception.
+//!
+//! Symbolizing a backtrace allocates and takes locks. The tracer thread does it in the crashing
+//! process, where it deadlocks if the stopped thread holds one of those locks (e.g. in the
+//! allocator, or the `stderr` lock). The helper is the same executable, started by [`start`] with
+//! an environment variable that diverts it before `main` (see [`RUN_IF_REQUESTED`]). The signal
+//! handler sends it the raw frame addresses and registers over a socket with async-signal-safe
+//! system calls, and waits for it to acknowledge that the report was printed.
+//!
+//! The executable and libraries are loaded at different addresses in the helper, so addresses are
+//! translated through the file offsets of the mappings in `/proc/<pid>/maps`.
+
+use crate::handler::MAX_FRAMES;
+use crate::stack;
+use log::debug;
+use std::cell::SyncUnsafeCell;
+use std::io::{self, Read, Write};
+use std::mem::{self, MaybeUninit};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::{fs, slice};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::{handler, x86_64::Registers};
+
+/// The environment variable that starts the executable as a helper. The value is the file
+/// descriptor of the socket.
+const ENV_VAR: &str = "BATMAN_HELPER";
+
+/// How long the signal handler waits for the helper to print the report, in milliseconds.
+const TIMEOUT_MS: libc::c_int = 3000;
+
+/// The report that the signal handler sends. The helper is the same executable, so the layout is
+/// the same on both ends.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Message {
+    len: usize,
+    /// The addresses start in the signal handler, instead of at the faulting instruction.
+    in_handler: bool,
+    addresses: [usize; MAX_FRAMES],
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    registers: Option<Registers>,
+}
+
+// The message is statically allocated, like the frames in `handler`. It is only written by the
+// signal handler that won the `HANDLING` race.
+static MESSAGE: SyncUnsafeCell<Message> = SyncUnsafeCell::new(Message {
+    len: 0,
+    in_handler: true,
+    addresses: [0; MAX_FRAMES],
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    registers: None,
+});
+
+// This end of the socket that is connected to the helper, or -1.
+static SOCKET: AtomicI32 = AtomicI32::new(-1);
+
+// Serializes `start`.
+static STARTED: Mutex<bool> = Mutex::new(false);
+
+/// See [`crate::start_helper`].
+pub(crate) fn start() -> io::Result<()> {
+    let mut started = STARTED.lock().unwrap_or_else(PoisonError::into_inner);
+    if *started {
+        return Ok(());
+    }
+
+    // Both ends are close-on-exec. The helper's end is inherited by clearing the flag after
+    // `fork`, so that no other child process keeps the socket open.
+    let (socket, remote) = UnixStream::pair()?;
+    let fd = remote.as_raw_fd();
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command.env(ENV_VAR, fd.to_string()).stdin(Stdio::null());
+    // SAFETY: `fcntl` is async-signal-safe.
+    unsafe {
+        command.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        });
+    }
+    let child = command.spawn()?;
+    drop(remote);
+
+    // Allow the helper to read the memory of this process (e.g. the operands of the faulting
+    // instruction) when Yama only allows tracing descendants. This fails without Yama.
+    // SAFETY: This only changes a process attribute.
+    unsafe {
+        libc::prctl(
+            libc::PR_SET_PTRACER,
+            libc::c_ulong::from(child.id()),
+            0,
+            0,
+            0,
+        )
+    };
+
+    debug!("Started helper process {}", child.id());
+    SOCKET.store(socket.into_raw_fd(), Ordering::Relaxed);
+    *started = true;
+
+    Ok(())
+}
+
+/// Send the frame addresses and registers to the helper, and wait for it to print the report.
+/// Returns `false` if there is no helper, or it did not print the report in time. `in_handler` is
+/// like [`stack::print_addresses`].
+///
+/// # Safety
+///
+/// Must only be called by the `SIGFPE` signal handler that won the `HANDLING` race.
+pub(crate) unsafe fn send(
+    addresses: impl Iterator<Item = usize>,
+    in_handler: bool,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] registers: Option<Registers>,
+) -> bool {
+    let fd = SOCKET.load(Ordering::Relaxed);
+    if fd < 0 {
+        return false;
+    }
+
+    let message = MESSAGE.get();
+    (*message).len = 0;
+    (*message).in_handler = in_handler;
+    for (slot, address) in (*message).addresses.iter_mut().zip(addresses) {
+        *slot = address;
+        (*message).len += 1;
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        (*message).registers = registers;
+    }
+
+    // `MSG_NOSIGNAL` prevents `SIGPIPE` if the helper exited.
+    let bytes = message.cast::<u8>();
+    let mut sent = 0;
+    while sent < mem::size_of::<Message>() {
+        let len = mem::size_of::<Message>() - sent;
+        let result = libc::send(fd, bytes.add(sent).cast(), len, libc::MSG_NOSIGNAL);
+        if result <= 0 {
+            return false;
+        }
+        sent += result as usize;
+    }
+
+    let mut poll = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    if libc::poll(&mut poll, 1, TIMEOUT_MS) != 1 {
+        return false;
+    }
+    let mut ack = 0_u8;
+
+    libc::recv(fd, (&mut ack as *mut u8).cast(), 1, 0) == 1
+}
+
+// Runs the helper before `main` if this process was started by `start`. The standard library can
+// be used in constructors on Linux.
+#[used]
+#[link_section = ".init_array"]
+static RUN_IF_REQUESTED: extern "C" fn() = {
+    extern "C" fn run_if_requested() {
+        let Some(fd) = std::env::var_os(ENV_VAR).and_then(|fd| fd.to_str()?.parse().ok()) else {
+            return;
+        };
+
+        let code = match serve(fd) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("batman: the helper process failed: {err}");
+                1
+            }
+        };
+
+        // SAFETY: The helper never returns to `main`.
+        unsafe { libc::_exit(code) };
+    }
+
+    run_if_requested
+};
+
+/// Print the reports that arrive on the socket, until the crashing process exits.
+fn serve(fd: RawFd) -> io::Result<()> {
+    // SAFETY: The descriptor was inherited from `start`, and nothing else uses it.
+    let mut socket = unsafe { UnixStream::from_raw_fd(fd) };
+
+    loop {
+        let mut message = MaybeUninit::<Message>::zeroed();
+        // SAFETY: The buffer is the size of `Message`, and it is initialized.
+        let bytes = unsafe {
+            slice::from_raw_parts_mut(message.as_mut_ptr().cast(), mem::size_of::<Message>())
+        };
+        match socket.read_exact(bytes) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+
+        // SAFETY: The message was written by the same executable.
+        report(unsafe { &message.assume_init() });
+        socket.write_all(&[1])?;
+    }
+}
+
+/// Print the report for a message from the parent process.
+fn report(message: &Message) {
+    // SAFETY: This system call has no preconditions.
+    let pid = unsafe { libc::getppid() };
+    let remote = read_maps(&pid.to_string());
+    let local = read_maps("self");
+    let translate = |address: usize| {
+        let (path, offset) = file_offset(&remote, address)?;
+        let mapping = local.iter().find(|mapping| {
+            mapping.path == path
+                && (mapping.offset..mapping.offset + mapping.len()).contains(&offset)
+        })?;
+
+        Some(mapping.start + offset - mapping.offset)
+    };
+
+    let len = message.len.min(MAX_FRAMES);
+    stack::print_addresses(
+        message.addresses[..len].to_vec(),
+        message.in_handler,
+        &translate,
+    );
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if let Some(registers) = &message.registers {
+        handler::print_registers(registers, pid, &translate);
+    }
+}
+
+/// A file mapping of a process.
+struct Mapping {
+    start: usize,
+    end: usize,
+    offset: usize,
+    path: String,
+}
+
+impl Mapping {
+    fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+/// Read the file mappings of a process from `/proc/<pid>/maps`. Anonymous mappings are skipped.
+fn read_maps(pid: &str) -> Vec<Mapping> {
+    let maps = fs::read_to_string(format!("/proc/{pid}/maps")).unwrap_or_default();
+
+    // Each line is `start-end perms offset dev inode path`, in hexadecimal.
+    maps.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(6, ' ');
+            let (start, end) = fields.next()?.split_once('-')?;
+            let offset = fields.nth(1)?;
+            let path = fields.nth(2)?.trim_start();
+            if !path.starts_with('/') {
+                return None;
+            }
+
+            Some(Mapping {
+                start: usize::from_str_radix(start, 16).ok()?,
+                end: usize::from_str_radix(end, 16).ok()?,
+                offset: usize::from_str_radix(offset, 16).ok()?,
+                path: path.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Find the file and the offset in it that is mapped at `address`.
+fn file_offset(maps: &[Mapping], address: usize) -> Option<(&str, usize)> {
+    let mapping = maps
+        .iter()
+        .find(|mapping| (mapping.start..mapping.end).contains(&address))?;
+
+    Some((&mapping.path, address - mapping.start + mapping.offset))
+}
@@ -1,15 +1,100 @@
 //! Terribly unsafe per-thread trapping exceptions for floating point operations.
 
-#![feature(asm_const)]
+#![feature(allocator_api)]
 #![feature(sync_unsafe_cell)]
 #![deny(clippy::all)]
 
+#[cfg(all(debug_assertions, target_arch = "aarch64"))]
+mod aarch64;
+#[cfg(debug_assertions)]
+mod backend;
+#[cfg(debug_assertions)]
+mod beacon;
+#[cfg(all(
+    debug_assertions,
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+mod all_threads;
+#[cfg(all(debug_assertions, unix))]
+mod altstack;
+mod denormal;
+mod fatal;
+#[cfg(all(
+    debug_assertions,
+    feature = "frame-pointers",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+mod frame_pointers;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod fenv;
+#[cfg(debug_assertions)]
+mod handler;
+#[cfg(all(debug_assertions, target_os = "linux"))]
+mod helper;
+pub mod poison;
+mod policy;
+#[cfg(all(
+    debug_assertions,
+    any(target_arch = "riscv32", target_arch = "riscv64"),
+    target_feature = "f"
+))]
+mod riscv;
+#[cfg(all(
+    debug_assertions,
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+mod resume;
+mod rounding;
+#[cfg(all(
+    debug_assertions,
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse2"
+))]
+mod simd;
+#[cfg(all(debug_assertions, target_os = "linux"))]
+mod sampler;
 #[cfg(debug_assertions)]
 mod stack;
-
+#[cfg(all(
+    debug_assertions,
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+mod suppress;
+pub mod thread;
 #[cfg(all(debug_assertions, any(target_arch = "x86", target_arch = "x86_64")))]
+mod watchdog;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod x86_64;
 
+pub use denormal::{with_denormals, DenormalMode};
+pub use fatal::{on_fatal, on_fatal_with_timeout, Report};
+pub use policy::{action, count, set_action, sites, Action, Exception, Site};
+pub use rounding::{with_rounding, RoundingMode};
+
+#[cfg(debug_assertions)]
+thread_local! {
+    // Set when `batman` enables exceptions on the thread, and cleared while `unguarded` masks
+    // them. This is read and written by signal handlers, so it must be const-initialized and must
+    // not need a destructor.
+    static ARMED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+
+    // Set while `unguarded` runs on the thread, so that `signal_all_threads` does not enable
+    // exceptions in the middle of the closure. This is read by a signal handler, so it has the
+    // same requirements as `ARMED`.
+    static UNGUARDED: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+
+    // The location of the last call to `checkpoint` on the thread. This is read by the sampler's
+    // signal handler, so it has the same requirements as `ARMED`.
+    static LAST_CHECKPOINT: std::cell::Cell<Option<&'static std::panic::Location<'static>>> =
+        const { std::cell::Cell::new(None) };
+}
+
 /// Enable hardware floating point exceptions.
 ///
 /// FPE configuration is only allowed once per-thread; subsequent calls will be a no-op. The
@@ -18,11 +103,13 @@ mod x86_64;
 /// - FPU "divide by zero" and "invalid operation" exceptions are enabled
 ///
 /// Specifically, `batman` does not concern itself with details like precision loss, rounding
-/// behavior, overflow/underflow, or handling subnormal numbers.
+/// behavior, overflow/underflow, or handling subnormal numbers by default. Other exceptions can be
+/// enabled, and the default handling can be changed, with [`set_action`].
 ///
 /// Threads inherit the FPU configuration from their parent (default disabled). Once enabled,
 /// exceptions cannot be disabled on the thread (at least not by `batman`; other `unsafe` code
-/// can disable exceptions at any time).
+/// can disable exceptions at any time). Use [`thread::spawn`] or [`thread::BuilderExt`] to
+/// enable exceptions on a new thread without enabling them on the parent.
 ///
 /// This function is a no-op when debug assertions are disabled.
 ///
@@ -41,229 +128,434 @@ mod x86_64;
 /// - No other library is allowed to install a `SIGFPE` signal handler unless it is synchronized
 ///   through a SemVer-compatible version of [`signal-hook-registry`].
 ///
+/// [`verify_installation`] checks these invariants on the current thread, and [`verify`] checks
+/// that the floating point environment was not changed.
+///
 /// [`signal-hook-registry`]: https://crates.io/crates/signal-hook-registry
 pub unsafe fn signal() -> std::io::Result<()> {
     #[cfg(debug_assertions)]
     {
-        use array_macro::array;
-        use backtrace::Frame;
-        use log::debug;
-        use std::sync::atomic::{AtomicBool, Ordering};
-        use std::{cell::SyncUnsafeCell, thread};
-        use std::{hint::unreachable_unchecked, sync::Arc};
-
-        #[cfg(windows)]
-        use windows_sys::Win32::System::{Diagnostics::Debug::RaiseFailFastException, Threading};
-
-        // 200 frames is less than 64 KiB on Windows x86_64, and about 6 KiB on Linux/macOS.
-        const MAX_FRAMES: usize = 200;
-
-        // Backtrace frames are statically allocated because heap allocations are not safe within
-        // signal handlers.
-        // See: https://www.man7.org/linux/man-pages/man7/signal-safety.7.html)
-        //
-        // # SAFETY:
-        //
-        // `Frame` implements `Send` and `Sync`. `SyncUnsafeCell` is used for interior mutability.
-        // All reads and writes are synchronized through the `FRAMES_AVAILABLE` and `FRAMES_HANDLED`
-        // beacons.
-        static FRAMES: [SyncUnsafeCell<Option<Frame>>; MAX_FRAMES] =
-            array![_ => SyncUnsafeCell::new(None); MAX_FRAMES];
-
-        // These atomics are used as beacons to communicate between the signal handler and the
-        // thread that prints the backtrace.
-        static FRAMES_AVAILABLE: AtomicBool = AtomicBool::new(false);
-        static FRAMES_HANDLED: AtomicBool = AtomicBool::new(false);
-
-        // This atomic makes the signal handler reentrant.
-        static HANDLING: AtomicBool = AtomicBool::new(false);
-
-        let id = thread::current().id();
-        debug!("Enabling FPU exceptions on thread {id:?}");
-
-        // Spawn a thread (called "tracer") that can use the standard library. The tracer prints the
-        // backtrace that it receives from the signal handler.
-        //
-        // The thread handle is wrapped in `Arc` so that it can be shared across signal handler
-        // instances. AArch64 macOS apparently raises SIGILL instead of SIGFPE for floating point
-        // exceptions, so this is a case we will need to handle appropriately.
-        let handle = Arc::new(thread::spawn(|| {
-            // We're not running in the signal handler, so we can do anything!
-            // However, we do need to ensure we are synchronized with the signal handler.
-            // Wait for the signal handler to unpark us AND for frames to be available.
-            loop {
-                thread::park();
-
-                if FRAMES_AVAILABLE.load(Ordering::Acquire) {
-                    break;
-                }
-            }
+        handler::install()?;
+        arm();
+    }
 
-            // Note that it is possible for the tracer thread to deadlock. E.g., if the signalling
-            // thread is holding the stdout or stderr locks. That's OK, because the signal handler
-            // will always terminate the process, even if we don't send a beacon back in a timely
-            // manner.
-            debug!("Received beacon, processing backtrace...");
-
-            // Sanity check: If this beacon is set, we're DOA.
-            assert!(!FRAMES_HANDLED.load(Ordering::Acquire));
-
-            // Show a pretty backtrace.
-            let mut frames = vec![];
-            for frame in FRAMES.iter() {
-                // SAFETY: This is the only thread accessing `FRAMES`, guaranteed by the atomic
-                // beacons and signal handler reentrancy guarantees.
-                match unsafe { &frame.get().read() } {
-                    Some(frame) => frames.push(frame.clone()),
-                    None => break,
-                }
-            }
-            stack::print(frames);
-
-            debug!("Sending beacon and stopping thread...");
-
-            // Send a beacon back to the signal handler.
-            FRAMES_HANDLED.store(true, Ordering::Release);
-
-            // All done! We can safely exit the tracer thread now.
-        }));
-
-        // SAFETY: This is a signal handler. It must be written with extreme care. The primary
-        // concerns from a POSIX point of view is that signal handlers are not allowed to touch
-        // global state unless it is done through synchronization with atomics, the number of
-        // libc/syscalls allowed is very limited, and reentrancy must be handled properly.
-        //
-        // Additionally, SIGFPE is an unrecoverable signal. This signal handler is not allowed to
-        // return normally, or send any signal or call any function that would cause the signalling
-        // thread to resume.
-        //
-        // Panics within a signal handler are forbidden. This signal handler terminates the process
-        // in a way that user code is not able to catch, i.e., unconditionally. This serves as both
-        // a way to terminate the process where a panic would normally be used, and also as the
-        // normal exit behavior for the signal handler. In other words, once invoked, this signal
-        // handler guarantees that the process will terminate in a finite time.
-        //
-        // See: https://www.man7.org/linux/man-pages/man7/signal-safety.7.html)
-        signal_hook_registry::register_signal_unchecked(libc::SIGFPE, move || {
-            let exch = HANDLING.compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire);
-            if exch.is_err() {
-                // The signal handler is already running and another thread has raised the signal.
-                // This handler is made reentrant by pausing the new thread forever. The process
-                // always terminates anyway.
-
-                #[cfg(unix)]
-                loop {
-                    libc::pause();
-                }
-
-                #[cfg(windows)]
-                loop {
-                    Threading::Sleep(Threading::INFINITE);
-                }
-
-                #[cfg(not(any(unix, windows)))]
-                libc::raise(libc::SIGKILL);
-            }
+    Ok(())
+}
 
-            let mut i = 0;
-            // SAFETY: We are certain that this is the only thread that gets here because of the
-            // `HANDLING` atomic.
-            //
-            // Note that we cannot use `std::backtrace` because it allocates on the heap and uses
-            // OS primitive locks (which are explicitly forbidden in signal handlers by POSIX).
-            // TODO: Make sure the `backtrace::trace_unsynchronized` does not allocate on the heap.
-            backtrace::trace_unsynchronized(|frame| {
-                // Cap the number of frames captured to fit in the static allocation.
-                if i >= MAX_FRAMES {
-                    return false;
-                }
-
-                // Insert the frame into the statically-allocated buffer.
-                //
-                // SAFETY: `i` is guaranteed in-bounds and there are no other readers or writers.
-                // Note that the `Index` implementation for `slice` has a conditional panic, but
-                // the bounds check ensures that the panic is not possible. It is always safe to
-                // drop the initial `None` values at each array index.
-                std::ptr::replace(FRAMES[i].get(), Some(frame.clone()));
-
-                i += 1;
-
-                true
-            });
-
-            // Send a beacon to alert the tracer thread that the frames are ready to be consumed.
-            let exch =
-                FRAMES_AVAILABLE.compare_exchange(false, true, Ordering::SeqCst, Ordering::Acquire);
-            if exch.is_err() {
-                // Sanity check: If this beacon is set, we're DOA.
-                fatal();
-            }
+/// Enable floating point exceptions on every thread in the process.
+///
+/// [`signal`] only configures the calling thread and the threads that it spawns afterward. Threads
+/// that already exist, like the thread pools and I/O threads started by dependencies before `main`
+/// gets control, are not covered. This function enables exceptions on the calling thread, then
+/// sends a private real-time signal to every other thread in the process (found in
+/// `/proc/self/task`). The signal handler enables exceptions on the thread that receives it.
+///
+/// `batman`'s own threads are left out, and threads that are inside [`unguarded`] are left alone.
+/// Threads that are armed this way do not get an alternate signal stack, so an exception on a
+/// thread that has almost exhausted its stack may not be reported.
+///
+/// The first real-time signal with a default disposition is claimed for this purpose. This
+/// function waits up to one second for every thread to handle the signal, and returns an error if
+/// any thread does not (e.g., because it blocks the signal).
+///
+/// This function is a no-op when debug assertions are disabled. It is only supported on Linux
+/// `x86` and `x86_64`; other platforms return an
+/// [`Unsupported`](std::io::ErrorKind::Unsupported) error.
+///
+/// # Safety
+///
+/// See [`signal`]. The invariants must be upheld on every thread in the process.
+pub unsafe fn signal_all_threads() -> std::io::Result<()> {
+    #[cfg(all(
+        debug_assertions,
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64")
+    ))]
+    all_threads::signal_all_threads()?;
+
+    #[cfg(all(
+        debug_assertions,
+        not(all(
+            target_os = "linux",
+            any(target_arch = "x86", target_arch = "x86_64")
+        ))
+    ))]
+    return Err(std::io::ErrorKind::Unsupported.into());
+
+    #[allow(unreachable_code)]
+    Ok(())
+}
 
-            // Unpark the tracer thread.
-            // TODO: Make sure this doesn't do anything that is signal-unsafe.
-            handle.thread().unpark();
-
-            // Wait for the tracer thread to print the backtrace. This is essentially a naive
-            // spinlock that is signal-safe. Timeout occurs after 3 seconds on Unix and Windows, or
-            // 30 seconds on anything else.
-            let mut counter = 30;
-            loop {
-                // Check the beacon from the tracer thread and the counter to handle timeouts.
-                if FRAMES_HANDLED.load(Ordering::Acquire) || counter == 0 {
-                    break;
-                }
-
-                // Sleep the signal handler for 100 ms.
-                #[cfg(unix)]
-                {
-                    let ts = libc::timespec {
-                        tv_sec: 0,
-                        tv_nsec: 100_000_000,
-                    };
-                    // SAFETY: `nanosleep` is not explicitly mentioned in POSIX async-signal-safety,
-                    // but Linux implements `sleep` (which _is_ signal-safe) via `nanosleep`.
-                    // Meanwhile, glibc claims that `sleep` is unsafe and `nanosleep` is safe!
-                    // See: https://www.gnu.org/software/libc/manual/html_node/Sleeping.html
-                    libc::nanosleep(&ts, std::ptr::null_mut());
-                }
-
-                #[cfg(windows)]
-                Threading::Sleep(100);
-
-                #[cfg(not(any(unix, windows)))]
-                libc::sleep(1);
-
-                counter -= 1;
-            }
+/// Run a closure with floating point exceptions temporarily disabled on the current thread.
+///
+/// All exceptions are masked while `f` runs. Afterward, any exception flags that `f` raised are
+/// cleared and the previous configuration is restored, even if `f` panics. This is intended for
+/// FFI calls and vectorized kernels that produce NaNs on purpose. Calls can be nested.
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// # use std::hint::black_box;
+/// unsafe { batman::signal()? };
+///
+/// let nan = batman::unguarded(|| black_box(0.0_f64) / black_box(0.0));
+/// assert!(nan.is_nan());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// This function only calls `f` when debug assertions are disabled.
+pub fn unguarded<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    #[cfg(debug_assertions)]
+    let _disarm = Disarm::new();
+    #[cfg(debug_assertions)]
+    let _guard = backend::MaskGuard::new();
 
-            fatal();
-        })?;
+    f()
+}
 
-        // Enable floating point exceptions.
-        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-        x86_64::enable_fp_exceptions();
+/// Clears [`ARMED`] and sets [`UNGUARDED`] until it is dropped, so that checkpoints, the sampler,
+/// and [`signal_all_threads`] ignore the thread while exceptions are masked.
+#[cfg(debug_assertions)]
+struct Disarm {
+    armed: bool,
+    unguarded: bool,
+}
 
-        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
-        compile_error!("Unsupported platform");
+#[cfg(debug_assertions)]
+impl Disarm {
+    fn new() -> Self {
+        Self {
+            armed: ARMED.replace(false),
+            unguarded: UNGUARDED.replace(true),
+        }
+    }
+}
 
-        debug!("FPU exceptions enabled on thread {id:?}");
+#[cfg(debug_assertions)]
+impl Drop for Disarm {
+    fn drop(&mut self) {
+        ARMED.set(self.armed);
+        UNGUARDED.set(self.unguarded);
+    }
+}
 
-        // I've seen things you people wouldn't believe.
-        // Attack ships on fire off the shoulder of Orion.
-        // I watched C-beams glitter in the dark near the Tannhauser gate.
-        // All those moments will be lost in time.
-        // Like tears in rain.
-        // Time to die...
-        unsafe fn fatal() -> ! {
-            #[cfg(not(windows))]
-            libc::raise(libc::SIGKILL);
+/// Check for floating point exceptions that were not trapped on the current thread.
+///
+/// Some processors do not support trapping floating point exceptions at all, like most AArch64
+/// cores (the trap enables are optional in the architecture). [`signal`] detects this, and falls
+/// back to recording exceptions in the sticky status flags. This function checks the flags, and
+/// raises `SIGFPE` if any enabled exception occurred since the last check. The backtrace points at
+/// the caller instead of the instruction that raised the exception, so checkpoints should be
+/// placed after each unit of work that should be checked (e.g. once per frame or per iteration of
+/// a solver).
+///
+/// In this mode, [`Action::Panic`], [`Action::WarnOnce`], and [`Action::Count`] are treated like
+/// [`Action::Abort`].
+///
+/// The report names the checkpoint that found the exception, and the previous checkpoint on the
+/// same thread. Long-running work between checkpoints can also be checked periodically with
+/// [`start_sampler`].
+///
+/// This function is a no-op when exceptions are trapped, and when debug assertions are disabled.
+#[track_caller]
+pub fn checkpoint() {
+    #[cfg(debug_assertions)]
+    if ARMED.get() && backend::is_polling() {
+        let location = std::panic::Location::caller();
+        let previous = LAST_CHECKPOINT.replace(Some(location));
+        let raised = backend::take_status(policy::enabled());
+        if !raised.is_empty() {
+            let names = raised.iter().map(|exception| exception.to_string());
+            let previous = previous
+                .map(|previous| format!(" (after the checkpoint at {previous})"))
+                .unwrap_or_default();
+            eprintln!(
+                "batman: floating point exception ({}) occurred before the checkpoint at \
+                {location}{previous}",
+                names.collect::<Vec<_>>().join(", "),
+            );
+
+            // The handler is installed on polling threads, and it terminates the process.
+            handler::raise();
+        }
+    }
+}
+
+/// Check that other code did not change the floating point environment of the current thread.
+///
+/// The invariants of [`signal`] forbid other code from changing the floating point environment,
+/// but a library (or a `-ffast-math` constructor in a C runtime) can still do it. The exception
+/// masks, denormal modes, rounding control, and x87 precision control are saved when the thread
+/// is armed. This function compares them with the live environment, and if any of them changed,
+/// it reports the bits that changed and raises `SIGFPE`. The backtrace points at the caller, so
+/// calls should be placed after code that is suspected of changing the environment (e.g. FFI
+/// calls). [`start_sampler`] also checks every armed thread periodically.
+///
+/// Changes made by [`with_denormals`], [`with_rounding`], and [`unguarded`] are expected.
+///
+/// This function is a no-op on threads that are not armed, and when debug assertions are
+/// disabled. It is only supported on `x86` and `x86_64`.
+#[track_caller]
+pub fn verify() {
+    #[cfg(all(debug_assertions, any(target_arch = "x86", target_arch = "x86_64")))]
+    if ARMED.get() {
+        let location = std::panic::Location::caller();
+        let previous = LAST_CHECKPOINT.replace(Some(location));
+        if let Some(changes) = watchdog::verify() {
+            let previous = previous
+                .map(|previous| format!(" (after the checkpoint at {previous})"))
+                .unwrap_or_default();
+            eprintln!(
+                "batman: floating point environment changed ({changes}) before the checkpoint at \
+                {location}{previous}",
+            );
+
+            // Restore the environment so that the handler does not misinterpret the change.
+            watchdog::restore();
+
+            // The handler is installed on armed threads, and it terminates the process.
+            handler::raise();
+        }
+    }
+}
 
-            #[cfg(windows)]
-            RaiseFailFastException(std::ptr::null(), std::ptr::null(), 0);
+/// Check the invariants that [`signal`] requires on the current thread.
+///
+/// The invariants are the caller's responsibility, and breaking them usually goes unnoticed until
+/// an exception is silently ignored. This function checks that:
+///
+/// - Exceptions are enabled on the current thread, and the enabled exceptions are still unmasked.
+/// - `SIGFPE` is not blocked in the signal mask of the current thread.
+/// - The process-wide `SIGFPE` handler is still the one that was installed by [`signal`].
+///
+/// ```
+/// # fn main() -> std::io::Result<()> {
+/// unsafe { batman::signal()? };
+/// batman::verify_installation()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Returns an error that lists every problem that was found. This function is a no-op when debug
+/// assertions are disabled.
+pub fn verify_installation() -> std::io::Result<()> {
+    #[cfg(debug_assertions)]
+    {
+        let mut problems = vec![];
+        if !ARMED.get() {
+            problems.push("exceptions are not enabled on this thread".to_string());
+        } else {
+            let masked = backend::masked(policy::enabled());
+            if !masked.is_empty() {
+                let names = masked.iter().map(|exception| exception.to_string());
+                problems.push(format!(
+                    "exceptions are masked on this thread ({})",
+                    names.collect::<Vec<_>>().join(", "),
+                ));
+            }
+        }
+        problems.extend(handler::installation_problems()?);
 
-            unreachable_unchecked();
+        if !problems.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "batman is not installed correctly: {}",
+                problems.join("; "),
+            )));
         }
     }
 
     Ok(())
 }
+
+/// Periodically check every armed thread for exceptions that were not trapped, and for changes to
+/// the floating point environment.
+///
+/// This complements [`checkpoint`] when exceptions are polled, and [`verify`] on `x86` and
+/// `x86_64`. A background thread interrupts every armed thread in the process with a private
+/// real-time signal once per `period`. The signal handler checks the status flags and the
+/// environment of the interrupted thread, and raises `SIGFPE` if any enabled exception occurred
+/// or the environment changed. The report names the last checkpoint that the thread passed, which
+/// narrows down the code that is responsible without adding more checkpoints.
+///
+/// Only the first call starts the sampler; subsequent calls are a no-op. The first real-time
+/// signal with a default disposition is claimed for this purpose (see [`signal_all_threads`]).
+///
+/// This function is a no-op when debug assertions are disabled. It is only supported on Linux;
+/// other platforms return an [`Unsupported`](std::io::ErrorKind::Unsupported) error.
+///
+/// # Safety
+///
+/// See [`signal`]. Additionally, blocking system calls on every thread may be interrupted by the
+/// signal, and fail with `EINTR` if they cannot be restarted.
+pub unsafe fn start_sampler(period: std::time::Duration) -> std::io::Result<()> {
+    #[cfg(all(debug_assertions, target_os = "linux"))]
+    sampler::start(period)?;
+
+    #[cfg(all(debug_assertions, not(target_os = "linux")))]
+    return Err(std::io::ErrorKind::Unsupported.into());
+
+    #[cfg(not(debug_assertions))]
+    let _ = period;
+
+    #[allow(unreachable_code)]
+    Ok(())
+}
+
+/// Print crash reports from a helper process instead of the process that raised the exception.
+///
+/// The report is normally printed by a thread in this process, which deadlocks if the thread that
+/// raised the exception holds a lock that printing needs (e.g. in the allocator, or the `stderr`
+/// lock). The signal handler waits for 3 seconds, and then terminates the process without a
+/// report. This function starts the current executable again as a helper process, which is
+/// diverted before `main`. The signal handler sends it the backtrace and registers with
+/// async-signal-safe system calls, and the helper symbolizes them and prints the report. The
+/// report is printed by this process if the helper is not running.
+///
+/// Only the first call starts the helper; subsequent calls are a no-op. The helper exits when
+/// this process does.
+///
+/// ```no_run
+/// # fn main() -> std::io::Result<()> {
+/// batman::start_helper()?;
+/// unsafe { batman::signal()? };
+/// # Ok(())
+/// # }
+/// ```
+///
+/// This function is a no-op when debug assertions are disabled. It is only supported on Linux;
+/// other platforms return an [`Unsupported`](std::io::ErrorKind::Unsupported) error.
+pub fn start_helper() -> std::io::Result<()> {
+    #[cfg(all(debug_assertions, target_os = "linux"))]
+    helper::start()?;
+
+    #[cfg(all(debug_assertions, not(target_os = "linux")))]
+    return Err(std::io::ErrorKind::Unsupported.into());
+
+    #[allow(unreachable_code)]
+    Ok(())
+}
+
+/// Suppress floating point exceptions raised by matching code.
+///
+/// Suppressed exceptions do not terminate the process. Instead, the faulting instruction is
+/// executed again with exceptions masked (producing its default result, like NaN), and exceptions
+/// are enabled again after it. This is intended for third-party code that cannot be fixed.
+///
+/// The pattern is either a symbol name or a source location:
+///
+/// - `libfoo::kernel*` matches any function with a demangled name that starts with
+///   `libfoo::kernel`. The hash suffix of Rust symbols is not part of the name.
+/// - `src/kernel.c:42` matches the code generated for line 42 of any file with a path that ends
+///   with `src/kernel.c`. This requires debug info.
+///
+/// `*` matches any sequence of characters and `?` matches any single character.
+///
+/// Patterns are resolved to address ranges immediately, so only the executable and the shared
+/// libraries that are already loaded can be matched. A suppressions file can also be loaded with
+/// [`suppress_file`] or by setting the `BATMAN_SUPPRESSIONS` environment variable to its path
+/// before calling [`signal`].
+///
+/// This function is a no-op when debug assertions are disabled. It is only supported on Linux
+/// `x86` and `x86_64`; other platforms return an
+/// [`Unsupported`](std::io::ErrorKind::Unsupported) error. It also returns that error if `SIGFPE`
+/// already had a handler when [`signal`] was called.
+pub fn suppress(pattern: &str) -> std::io::Result<()> {
+    #[cfg(all(
+        debug_assertions,
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64")
+    ))]
+    suppress::suppress(&[pattern])?;
+
+    #[cfg(all(
+        debug_assertions,
+        not(all(
+            target_os = "linux",
+            any(target_arch = "x86", target_arch = "x86_64")
+        ))
+    ))]
+    return Err(std::io::ErrorKind::Unsupported.into());
+
+    #[allow(unreachable_code)]
+    {
+        let _ = pattern;
+        Ok(())
+    }
+}
+
+/// Load suppressions from a file.
+///
+/// The file has one [`suppress`] pattern per line. Blank lines and lines starting with `#` are
+/// ignored.
+///
+/// ```text
+/// # Third-party kernels pad SIMD tails with zeros.
+/// libfoo::kernel*
+/// vendor/fastmath/src/simd.c:117
+/// ```
+pub fn suppress_file<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<()> {
+    #[cfg(all(
+        debug_assertions,
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64")
+    ))]
+    suppress::suppress_file(path.as_ref())?;
+
+    #[cfg(all(
+        debug_assertions,
+        not(all(
+            target_os = "linux",
+            any(target_arch = "x86", target_arch = "x86_64")
+        ))
+    ))]
+    return Err(std::io::ErrorKind::Unsupported.into());
+
+    #[allow(unreachable_code)]
+    {
+        let _ = path;
+        Ok(())
+    }
+}
+
+/// Enable floating point exceptions on the current thread.
+///
+/// # Safety
+///
+/// The signal handler must already be installed with [`handler::install`]. See [`signal`] for
+/// the remaining invariants.
+#[cfg(debug_assertions)]
+pub(crate) unsafe fn arm() {
+    use log::debug;
+    use std::thread;
+
+    if ARMED.get() {
+        return;
+    }
+
+    let id = thread::current().id();
+    debug!("Enabling FPU exceptions on thread {id:?}");
+
+    // Enable floating point exceptions.
+    backend::enable(policy::enabled());
+
+    // The handler needs more stack than the thread may have left when an exception is raised.
+    #[cfg(unix)]
+    altstack::install();
+
+    #[cfg(all(
+        feature = "frame-pointers",
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64")
+    ))]
+    frame_pointers::record_stack();
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    watchdog::arm();
+
+    ARMED.set(true);
+
+    debug!("FPU exceptions enabled on thread {id:?}");
+}
@@ -0,0 +1,255 @@
+//! Signaling NaN poisoning, to catch reads of uninitialized floating point memory.
+//!
+//! A signaling NaN (sNaN) can be copied around freely, but any arithmetic or comparison that uses
+//! one raises the "invalid operation" exception. Filling memory with sNaNs before it is
+//! initialized means that the first read of an uninitialized value is caught by [`signal`], with a
+//! backtrace pointing at the reader.
+//!
+//! ```no_run
+//! use batman::poison::{self, Poisoned};
+//!
+//! unsafe { batman::signal().unwrap() };
+//!
+//! let mut samples = Poisoned::<Vec<f64>>::with_len(4);
+//! samples[..3].copy_from_slice(&[1.0, 2.0, 3.0]);
+//!
+//! // Oops, off by one! This terminates the process.
+//! let sum: f64 = samples.iter().sum();
+//! ```
+//!
+//! [`signal`]: crate::signal
+
+use std::alloc::{AllocError, Allocator, Global, Layout};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl Sealed for f32 {}
+    impl Sealed for f64 {}
+}
+
+/// Floating point types that have a signaling NaN.
+pub trait Float: Copy + sealed::Sealed {
+    /// The signaling NaN that `batman` uses for poisoning.
+    const SIGNALING_NAN: Self;
+
+    /// Check if a value is a signaling NaN, without raising an exception.
+    fn is_signaling_nan(self) -> bool;
+}
+
+impl Float for f32 {
+    const SIGNALING_NAN: Self = f32::from_bits(0x7fa0_0000);
+
+    fn is_signaling_nan(self) -> bool {
+        const QUIET: u32 = 1 << 22;
+        let bits = self.to_bits() & !(1 << 31);
+        bits > f32::INFINITY.to_bits() && bits & QUIET == 0
+    }
+}
+
+impl Float for f64 {
+    const SIGNALING_NAN: Self = f64::from_bits(0x7ff4_0000_0000_0000);
+
+    fn is_signaling_nan(self) -> bool {
+        const QUIET: u64 = 1 << 51;
+        let bits = self.to_bits() & !(1 << 63);
+        bits > f64::INFINITY.to_bits() && bits & QUIET == 0
+    }
+}
+
+/// Fill a slice with signaling NaNs.
+pub fn fill<F: Float>(slice: &mut [F]) {
+    slice.fill(F::SIGNALING_NAN);
+}
+
+/// A container that is filled with signaling NaNs wherever it has not been written.
+///
+/// `Poisoned` dereferences to the inner container, so it can be used in place of it.
+#[derive(Clone, Debug)]
+pub struct Poisoned<T>(T);
+
+impl<T> Poisoned<T> {
+    /// Unwrap the inner container.
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<F: Float> Poisoned<Vec<F>> {
+    /// Create a vector of `len` signaling NaNs.
+    pub fn with_len(len: usize) -> Self {
+        Self(vec![F::SIGNALING_NAN; len])
+    }
+
+    /// Resize the vector in place, filling new elements with signaling NaNs.
+    pub fn resize(&mut self, new_len: usize) {
+        self.0.resize(new_len, F::SIGNALING_NAN);
+    }
+}
+
+/// An empty vector.
+impl<F: Float> Default for Poisoned<Vec<F>> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<F: Float, const N: usize> Poisoned<[F; N]> {
+    /// Create an array of signaling NaNs.
+    pub fn new() -> Self {
+        Self([F::SIGNALING_NAN; N])
+    }
+}
+
+/// An array of signaling NaNs, like [`Poisoned::new`].
+impl<F: Float, const N: usize> Default for Poisoned<[F; N]> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deref for Poisoned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Poisoned<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// An [`Allocator`] adapter that fills new memory with signaling NaNs.
+///
+/// The float type is chosen by the collection that uses the allocator, e.g.
+/// `Vec<f32, PoisonAlloc<f32>>`, so that only float-typed allocations are poisoned. The element
+/// type of the collection must match `F`, or the poison is not a NaN. Memory is
+/// poisoned when it is allocated and when an allocation grows. Memory that is requested to be
+/// zeroed is not poisoned.
+///
+/// ```
+/// #![feature(allocator_api)]
+///
+/// use batman::poison::{Float as _, PoisonAlloc};
+///
+/// let mut samples = Vec::with_capacity_in(4, PoisonAlloc::<f32>::new());
+/// samples.push(1.0_f32);
+///
+/// # if cfg!(debug_assertions) {
+/// // SAFETY: The allocator initialized the memory.
+/// unsafe { samples.set_len(4) };
+/// assert!(samples[3].is_signaling_nan());
+/// # }
+/// ```
+///
+/// The allocator only poisons memory when debug assertions are enabled.
+pub struct PoisonAlloc<F: Float, A: Allocator = Global> {
+    alloc: A,
+    _float: PhantomData<F>,
+}
+
+impl<F: Float> PoisonAlloc<F> {
+    /// Create an allocator adapter for the global allocator.
+    pub const fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl<F: Float> Default for PoisonAlloc<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Float, A: Allocator> PoisonAlloc<F, A> {
+    /// Create an allocator adapter for another allocator.
+    pub const fn new_in(alloc: A) -> Self {
+        Self {
+            alloc,
+            _float: PhantomData,
+        }
+    }
+
+    /// Fill the allocation with signaling NaNs, starting at element-aligned `offset` bytes.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for writes.
+    unsafe fn poison(ptr: NonNull<[u8]>, offset: usize) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+
+        // The memory is uninitialized, so it is only written through raw pointers.
+        let nan = F::SIGNALING_NAN;
+        let size = size_of::<F>();
+        let base = ptr.cast::<u8>().as_ptr();
+        let mut start = offset.next_multiple_of(size);
+        while start + size <= ptr.len() {
+            // SAFETY: Floats do not have padding bytes, and the chunk is in bounds.
+            ptr::copy_nonoverlapping(ptr::from_ref(&nan).cast::<u8>(), base.add(start), size);
+            start += size;
+        }
+    }
+}
+
+impl<F: Float, A: Allocator + Clone> Clone for PoisonAlloc<F, A> {
+    fn clone(&self) -> Self {
+        Self::new_in(self.alloc.clone())
+    }
+}
+
+// SAFETY: All allocations are delegated to the inner allocator.
+unsafe impl<F: Float, A: Allocator> Allocator for PoisonAlloc<F, A> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.alloc.allocate(layout)?;
+        // SAFETY: The allocation is valid for writes.
+        unsafe { Self::poison(ptr, 0) };
+
+        Ok(ptr)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc.allocate_zeroed(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.alloc.deallocate(ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.alloc.grow(ptr, old_layout, new_layout)?;
+        Self::poison(ptr, old_layout.size());
+
+        Ok(ptr)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc.grow_zeroed(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.alloc.shrink(ptr, old_layout, new_layout)
+    }
+}
@@ -0,0 +1,450 @@
+//! Per-exception configuration of what happens when a floating point exception is raised.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+#[cfg(debug_assertions)]
+use array_macro::array;
+
+/// Floating point exceptions, as defined by IEEE 754.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Exception {
+    /// The operation has no meaningful result, e.g. `0.0 / 0.0` or `(-1.0).sqrt()`. The default
+    /// result is NaN.
+    InvalidOperation,
+    /// An operand is subnormal. This is not an IEEE 754 exception, but subnormal operands are
+    /// much slower than normal numbers on most processors. Operations that produce a subnormal
+    /// result raise [`Exception::Underflow`] instead. Not raised when subnormal operands are
+    /// treated as zero; see [`with_denormals`](crate::with_denormals).
+    Denormal,
+    /// A finite non-zero number was divided by zero. The default result is infinity.
+    DivideByZero,
+    /// The result is too large to be represented, and is rounded to infinity or the largest
+    /// finite number.
+    Overflow,
+    /// The result is too small to be represented as a normal number.
+    Underflow,
+    /// The result was rounded. Almost every operation raises this exception.
+    Inexact,
+}
+
+/// What `batman` does when a floating point exception is raised.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum Action {
+    /// Print a backtrace and terminate the process. This is the default action for
+    /// [`Exception::InvalidOperation`] and [`Exception::DivideByZero`].
+    Abort,
+    /// Unwind the thread with a panic, e.g. `floating point exception: invalid operation`, so that
+    /// destructors run.
+    ///
+    /// Rust can only unwind from function calls, so the thread continues with exceptions masked
+    /// until the next call or return, and the panic is raised there. The faulting instruction and
+    /// the instructions after it produce their default results. The process aborts if the next
+    /// call is to a function that cannot unwind, like `memcpy`, in a function with destructors.
+    /// If the thread does not call or return within 100,000 instructions (e.g. in a long loop
+    /// without calls), the exception is reported and the process is terminated like
+    /// [`Action::Abort`].
+    ///
+    /// The panic is raised in code that the compiler assumed could not panic. With optimizations,
+    /// LLVM infers that functions without calls cannot unwind, and removes the landing pads
+    /// around calls to them, including the one in [`catch_unwind`](std::panic::catch_unwind).
+    /// [`set_action`] refuses this action when debug assertions are disabled, and when panics
+    /// abort. Builds that enable debug assertions with optimizations must not rely on catching
+    /// the panic.
+    Panic,
+    /// Print a warning to `stderr` the first time each instruction raises the exception, and
+    /// continue.
+    WarnOnce,
+    /// Count the exception and continue. See [`count`].
+    Count,
+    /// Do not enable the exception. This is the default action for all other exceptions.
+    Ignore,
+}
+
+impl Exception {
+    /// All exceptions.
+    #[cfg(debug_assertions)]
+    pub(crate) const ALL: [Self; 6] = [
+        Self::InvalidOperation,
+        Self::Denormal,
+        Self::DivideByZero,
+        Self::Overflow,
+        Self::Underflow,
+        Self::Inexact,
+    ];
+
+    /// The bit for this exception in [`Exceptions`].
+    const fn bit(self) -> u8 {
+        match self {
+            Self::InvalidOperation => 1 << 0,
+            Self::Denormal => 1 << 1,
+            Self::DivideByZero => 1 << 2,
+            Self::Overflow => 1 << 3,
+            Self::Underflow => 1 << 4,
+            Self::Inexact => 1 << 5,
+        }
+    }
+
+    /// The index of this exception in the configuration tables.
+    const fn index(self) -> usize {
+        self.bit().trailing_zeros() as usize
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidOperation => "invalid operation",
+            Self::Denormal => "denormal operand",
+            Self::DivideByZero => "division by zero",
+            Self::Overflow => "overflow",
+            Self::Underflow => "underflow",
+            Self::Inexact => "inexact result",
+        })
+    }
+}
+
+// The `u8` representation is ordered by severity, with the most severe action first.
+impl Action {
+    const fn from_u8(action: u8) -> Self {
+        match action {
+            0 => Self::Abort,
+            1 => Self::Panic,
+            2 => Self::WarnOnce,
+            3 => Self::Count,
+            _ => Self::Ignore,
+        }
+    }
+
+    const fn to_u8(self) -> u8 {
+        match self {
+            Self::Abort => 0,
+            Self::Panic => 1,
+            Self::WarnOnce => 2,
+            Self::Count => 3,
+            Self::Ignore => 4,
+        }
+    }
+}
+
+/// A set of exceptions. The bit order matches the x86 exception flags and masks.
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Exceptions(u8);
+
+#[cfg(debug_assertions)]
+impl Exceptions {
+    /// Every exception.
+    pub(crate) const ALL: Self = Self(0x3f);
+
+    pub(crate) const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub(crate) const fn from_bits(bits: u8) -> Self {
+        Self(bits & Self::ALL.0)
+    }
+
+    pub(crate) const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub(crate) const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub(crate) const fn contains(self, exception: Exception) -> bool {
+        self.0 & exception.bit() != 0
+    }
+
+    pub(crate) fn iter(self) -> impl Iterator<Item = Exception> {
+        Exception::ALL
+            .into_iter()
+            .filter(move |&exception| self.contains(exception))
+    }
+}
+
+#[cfg(debug_assertions)]
+impl FromIterator<Exception> for Exceptions {
+    fn from_iter<I: IntoIterator<Item = Exception>>(iter: I) -> Self {
+        Self(iter.into_iter().fold(0, |bits, exception| bits | exception.bit()))
+    }
+}
+
+// Indexed by `Exception::index`. These are read by the signal handler.
+static ACTIONS: [AtomicU8; 6] = [
+    AtomicU8::new(Action::Abort.to_u8()),
+    AtomicU8::new(Action::Ignore.to_u8()),
+    AtomicU8::new(Action::Abort.to_u8()),
+    AtomicU8::new(Action::Ignore.to_u8()),
+    AtomicU8::new(Action::Ignore.to_u8()),
+    AtomicU8::new(Action::Ignore.to_u8()),
+];
+static COUNTS: [AtomicU64; 6] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+/// Configure what happens when an exception is raised.
+///
+/// Exceptions with [`Action::Ignore`] are masked, and all others are unmasked, when a thread
+/// calls [`signal`](crate::signal). Configure the actions before calling `signal`; changing an
+/// action to or from [`Action::Ignore`] does not affect threads that are already configured.
+///
+/// ```no_run
+/// use batman::{Action, Exception};
+///
+/// // `1.0 / 0.0` is infinity on purpose, but NaN is always a bug.
+/// batman::set_action(Exception::DivideByZero, Action::WarnOnce).unwrap();
+/// batman::set_action(Exception::Overflow, Action::Count).unwrap();
+///
+/// unsafe { batman::signal().unwrap() };
+/// ```
+///
+/// Actions other than [`Action::Abort`] and [`Action::Ignore`] resume the thread, which is only
+/// supported on Linux `x86` and `x86_64`. On other platforms they are treated like
+/// [`Action::Abort`]. They are also treated like [`Action::Abort`] if `SIGFPE` already had a
+/// handler when [`signal`](crate::signal) was called; a warning is logged.
+///
+/// # Errors
+///
+/// Returns an [`Unsupported`](std::io::ErrorKind::Unsupported) error for [`Action::Panic`] when
+/// debug assertions are disabled or the panic strategy is `abort`. The action is not changed.
+pub fn set_action(exception: Exception, action: Action) -> std::io::Result<()> {
+    if action == Action::Panic && (cfg!(not(debug_assertions)) || cfg!(panic = "abort")) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "`Action::Panic` requires debug assertions and `panic = \"unwind\"`",
+        ));
+    }
+
+    ACTIONS[exception.index()].store(action.to_u8(), Ordering::Relaxed);
+
+    #[cfg(all(
+        debug_assertions,
+        target_os = "linux",
+        any(target_arch = "x86", target_arch = "x86_64")
+    ))]
+    if matches!(action, Action::Panic | Action::WarnOnce | Action::Count) {
+        // SAFETY: Resuming the thread is the purpose of these actions.
+        if let Err(err) = unsafe { crate::resume::install() } {
+            log::warn!("Unable to install the SIGTRAP handler, {exception} will abort: {err}");
+        }
+        if let Err(err) = crate::handler::check_capture() {
+            log::warn!("Unable to resume the thread, {exception} will abort: {err}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the configured action for an exception. See [`set_action`].
+pub fn action(exception: Exception) -> Action {
+    Action::from_u8(ACTIONS[exception.index()].load(Ordering::Relaxed))
+}
+
+/// Get the number of times an exception has been raised with [`Action::WarnOnce`] or
+/// [`Action::Count`].
+///
+/// This is always zero when debug assertions are disabled.
+pub fn count(exception: Exception) -> u64 {
+    COUNTS[exception.index()].load(Ordering::Relaxed)
+}
+
+/// An instruction that raised an exception with [`Action::WarnOnce`] or [`Action::Count`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Site {
+    /// The address of the instruction. It can be symbolized with the `backtrace` crate.
+    pub address: usize,
+    /// The exception that was raised.
+    pub exception: Exception,
+    /// The number of times the instruction raised the exception.
+    pub count: u64,
+}
+
+/// Get the instructions that raised exceptions with [`Action::WarnOnce`] or [`Action::Count`],
+/// most frequent first.
+///
+/// This is useful to find the code that needs to be fixed, e.g. where subnormals should be
+/// flushed to zero:
+///
+/// ```no_run
+/// use batman::{Action, Exception};
+///
+/// batman::set_action(Exception::Denormal, Action::Count);
+/// unsafe { batman::signal().unwrap() };
+///
+/// // ...
+///
+/// for site in batman::sites() {
+///     backtrace::resolve(site.address as *mut _, |symbol| {
+///         eprintln!("{}: {:?} {:?}", site.count, symbol.name(), symbol.lineno());
+///     });
+/// }
+/// ```
+///
+/// Up to 1,024 instructions are tracked; exceptions raised by other instructions are only
+/// included in [`count`]. This is always empty when debug assertions are disabled.
+pub fn sites() -> Vec<Site> {
+    #[cfg(debug_assertions)]
+    {
+        let mut sites: Vec<_> = SITES
+            .iter()
+            .zip(&SITE_COUNTS)
+            .filter_map(|(key, count)| {
+                let key = key.load(Ordering::Relaxed);
+                let index = (key & 0x7) as usize;
+                let exception = Exception::ALL
+                    .into_iter()
+                    .find(|exception| exception.index() == index)?;
+
+                Some(Site {
+                    address: (key >> 3) as usize,
+                    exception,
+                    count: count.load(Ordering::Relaxed),
+                })
+            })
+            .filter(|site| site.count > 0)
+            .collect();
+        sites.sort_by_key(|site| std::cmp::Reverse(site.count));
+
+        sites
+    }
+
+    #[cfg(not(debug_assertions))]
+    Vec::new()
+}
+
+/// The exceptions that are enabled by [`signal`](crate::signal).
+#[cfg(debug_assertions)]
+pub(crate) fn enabled() -> Exceptions {
+    Exception::ALL
+        .into_iter()
+        .filter(|&exception| action(exception) != Action::Ignore)
+        .collect()
+}
+
+// The number of instructions that can be tracked for `Action::WarnOnce` and `Action::Count`. Once
+// the table is full, instructions that are not already in it warn every time.
+#[cfg(debug_assertions)]
+const MAX_SITES: usize = 1024;
+
+// Open addressing hash table of instructions that raised exceptions. Each key is the instruction
+// address shifted left by 3 bits, combined with the exception index.
+#[cfg(debug_assertions)]
+static SITES: [AtomicU64; MAX_SITES] = array![_ => AtomicU64::new(0); MAX_SITES];
+// The number of times each instruction in `SITES` raised the exception.
+#[cfg(debug_assertions)]
+static SITE_COUNTS: [AtomicU64; MAX_SITES] = array![_ => AtomicU64::new(0); MAX_SITES];
+
+/// Decide what to do about exceptions raised by the instruction at `address`.
+///
+/// The exceptions are counted, and warnings are printed. The most severe of the configured
+/// actions is returned. This function is async-signal-safe.
+#[cfg(debug_assertions)]
+pub(crate) fn handle(raised: Exceptions, address: usize) -> Action {
+    let mut result: Option<Action> = None;
+
+    for exception in raised.iter() {
+        let action = action(exception);
+        match action {
+            Action::Abort | Action::Panic | Action::Ignore => (),
+            Action::WarnOnce => {
+                COUNTS[exception.index()].fetch_add(1, Ordering::Relaxed);
+                if record(exception, address) {
+                    warn(exception, address);
+                }
+            }
+            Action::Count => {
+                COUNTS[exception.index()].fetch_add(1, Ordering::Relaxed);
+                record(exception, address);
+            }
+        }
+
+        result = match result {
+            Some(result) if result.to_u8() <= action.to_u8() => Some(result),
+            _ => Some(action),
+        };
+    }
+
+    // Anything that is not recognized is fatal.
+    result.unwrap_or(Action::Abort)
+}
+
+/// Record that an instruction raised an exception, returning `true` the first time.
+#[cfg(debug_assertions)]
+fn record(exception: Exception, address: usize) -> bool {
+    let key = (address as u64) << 3 | exception.index() as u64;
+    // Fibonacci hashing.
+    let hash = key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> (64 - MAX_SITES.trailing_zeros());
+
+    for i in 0..MAX_SITES {
+        let index = (hash as usize + i) % MAX_SITES;
+        let first =
+            match SITES[index].compare_exchange(0, key, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => true,
+                Err(existing) if existing == key => false,
+                Err(_) => continue,
+            };
+        SITE_COUNTS[index].fetch_add(1, Ordering::Relaxed);
+
+        return first;
+    }
+
+    true
+}
+
+/// Print a warning to `stderr` without allocating or taking locks.
+#[cfg(debug_assertions)]
+fn warn(exception: Exception, address: usize) {
+    use std::fmt::Write as _;
+
+    let mut buffer = StackBuffer::<128>::new();
+    let _ = writeln!(
+        buffer,
+        "batman: floating point exception ({exception}) at {address:#x}; continuing"
+    );
+
+    // SAFETY: `write` is async-signal-safe.
+    unsafe { libc::write(2, buffer.as_bytes().as_ptr().cast(), buffer.len) };
+}
+
+/// A fixed-size buffer for formatting text in a signal handler. Text that does not fit is
+/// truncated.
+#[cfg(debug_assertions)]
+pub(crate) struct StackBuffer<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+}
+
+#[cfg(debug_assertions)]
+impl<const N: usize> StackBuffer<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            len: 0,
+        }
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+#[cfg(debug_assertions)]
+impl<const N: usize> fmt::Write for StackBuffer<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(N - self.len);
+        self.buffer[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+
+        Ok(())
+    }
+}
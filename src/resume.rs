@@ -0,0 +1,300 @@
+//! Resume a thread after a floating point exception instead of terminating the process.
+//!
+//! The faulting instruction is executed again with all exceptions masked, so it produces its
+//! default result (e.g., NaN or infinity). The trap flag is set so that the CPU raises `SIGTRAP`
+//! after that one instruction, and the `SIGTRAP` handler restores the exception masks.
+//!
+//! Note that x87 exceptions are raised by the _next_ x87 instruction after the one that caused
+//! them. The instruction that caused the exception has already completed, so its result is not
+//! replaced with the default result.
+//!
+//! To panic instead, the thread keeps single-stepping with exceptions masked until it executes a
+//! call or a return. Rust can only unwind from call sites, so the panic is raised there: a call
+//! is redirected to [`trampoline`] instead of the callee, and after a return, the trampoline is
+//! called as if by the call that just returned. A thread that does not reach a call or return
+//! within [`MAX_STEPS`] instructions (e.g. in a long loop without calls) is reported and
+//! terminated like [`Action::Abort`](crate::Action::Abort) instead.
+
+use crate::handler;
+use crate::policy::{Exception, StackBuffer};
+use crate::x86_64::{self, Masks};
+use log::debug;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::{cell::Cell, ffi::c_void, io, mem, ptr};
+
+/// The size of a return address on the stack.
+const WORD: usize = mem::size_of::<usize>();
+/// The maximum length of an x86 instruction.
+const MAX_INSTRUCTION_LEN: usize = 15;
+/// The number of instructions that are single-stepped to find a call or return before giving up
+/// on a panic. Each one is a `SIGTRAP`, so this takes well under a second.
+const MAX_STEPS: u32 = 100_000;
+
+thread_local! {
+    // The exception masks to restore after single-stepping the faulting instruction.
+    static STEPPING: Cell<Option<Masks>> = const { Cell::new(None) };
+
+    // The state before the last step while stepping to a call or return to panic.
+    static UNWINDING: Cell<Option<Step>> = const { Cell::new(None) };
+
+    // The exception that `trampoline` panics with.
+    static EXCEPTION: Cell<Option<Exception>> = const { Cell::new(None) };
+}
+
+/// See [`UNWINDING`].
+#[derive(Clone, Copy)]
+struct Step {
+    exception: Exception,
+    /// The address of the instruction that is stepped next.
+    ip: usize,
+    /// The number of instructions stepped so far.
+    steps: u32,
+}
+
+// The `SIGTRAP` disposition that was replaced by `install`.
+static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+// Serializes `install`.
+static INSTALL: Mutex<()> = Mutex::new(());
+
+/// Install the `SIGTRAP` handler. This is idempotent.
+pub(crate) unsafe fn install() -> io::Result<()> {
+    let _guard = INSTALL.lock().unwrap_or_else(PoisonError::into_inner);
+    if PREVIOUS.get().is_some() {
+        return Ok(());
+    }
+
+    let mut new: libc::sigaction = mem::zeroed();
+    new.sa_sigaction = sigtrap as *const () as usize;
+    new.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+
+    let mut old: libc::sigaction = mem::zeroed();
+    if libc::sigaction(libc::SIGTRAP, &new, &mut old) != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let _ = PREVIOUS.set(old);
+
+    debug!("Installed SIGTRAP handler");
+
+    Ok(())
+}
+
+/// Returns `true` while the current thread is single-stepping a faulting instruction with
+/// exceptions masked.
+pub(crate) fn is_stepping() -> bool {
+    STEPPING.get().is_some()
+}
+
+/// Arrange for the thread that raised `SIGFPE` to resume without terminating the process.
+///
+/// Returns `false` if the thread cannot be resumed, e.g. because the `SIGTRAP` handler is not
+/// installed or the context does not have the FPU state.
+///
+/// # Safety
+///
+/// Must only be called by the `SIGFPE` signal handler on the thread that raised the signal.
+/// `context` must be the `ucontext_t` pointer passed to the signal handler.
+pub(crate) unsafe fn resume(context: *mut libc::ucontext_t) -> bool {
+    if PREVIOUS.get().is_none() {
+        return false;
+    }
+
+    match x86_64::mask_in_context(context) {
+        Some(masks) => {
+            STEPPING.set(Some(masks));
+            x86_64::single_step_in_context(context, true);
+
+            true
+        }
+        None => false,
+    }
+}
+
+/// Arrange for the thread that raised `SIGFPE` to panic with `exception` at the next call or
+/// return.
+///
+/// Returns `false` if the thread cannot be resumed. See [`resume`].
+///
+/// # Safety
+///
+/// See [`resume`].
+pub(crate) unsafe fn panic(context: *mut libc::ucontext_t, exception: Exception) -> bool {
+    if !resume(context) {
+        return false;
+    }
+
+    UNWINDING.set(Some(Step {
+        exception,
+        ip: x86_64::ip_in_context(context),
+        steps: 0,
+    }));
+
+    true
+}
+
+/// Redirect the thread to [`trampoline`] if the last step executed a call or a return. Returns
+/// `false` if it did not.
+///
+/// The instruction that was stepped is decoded, because the change in the stack pointer does not
+/// tell a call or return apart from a `push`, `pop`, or stack adjustment.
+unsafe fn redirect(context: *mut libc::ucontext_t, step: Step) -> bool {
+    match decode(step.ip) {
+        // Replace the callee. The return address on the stack is in the caller's call site.
+        Transfer::Call => {
+            x86_64::set_ip_in_context(context, trampoline as *const () as usize);
+        }
+
+        // Call the trampoline from the instruction that the return popped, which is after a call.
+        Transfer::Return => {
+            let ip = x86_64::ip_in_context(context);
+            let sp = x86_64::sp_in_context(context) - WORD;
+            ptr::write(sp as *mut usize, ip);
+            x86_64::set_sp_in_context(context, sp);
+            x86_64::set_ip_in_context(context, trampoline as *const () as usize);
+        }
+
+        Transfer::Other => return false,
+    }
+
+    true
+}
+
+/// The control transfers that [`redirect`] is interested in.
+enum Transfer {
+    Call,
+    Return,
+    Other,
+}
+
+/// Decode the instruction at `ip`, which the thread just executed. Only near calls and returns
+/// are recognized; far calls and returns do not occur in Rust code.
+///
+/// # Safety
+///
+/// `ip` must be the address of an instruction that was executed.
+unsafe fn decode(ip: usize) -> Transfer {
+    // Skip the prefixes, e.g. `notrack` on indirect calls. Each byte up to the opcode is part of
+    // the instruction, so it is mapped.
+    for i in 0..MAX_INSTRUCTION_LEN {
+        match ptr::read((ip + i) as *const u8) {
+            // Operand size, address size, segment override, lock, and repeat prefixes.
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3 => (),
+            // REX prefixes. On `x86`, these are the one-byte `inc` and `dec` instructions.
+            0x40..=0x4f if cfg!(target_arch = "x86_64") => (),
+            // `call rel32`
+            0xe8 => return Transfer::Call,
+            // `call r/m`, which is `0xff /2`.
+            0xff => {
+                let modrm = ptr::read((ip + i + 1) as *const u8);
+                return match (modrm >> 3) & 0x7 {
+                    2 => Transfer::Call,
+                    _ => Transfer::Other,
+                };
+            }
+            // `ret` and `ret imm16`
+            0xc2 | 0xc3 => return Transfer::Return,
+            _ => return Transfer::Other,
+        }
+    }
+
+    Transfer::Other
+}
+
+/// Panic with the exception that the `SIGTRAP` handler saved. The handler calls this function
+/// by changing the instruction pointer of the thread, so that the panic unwinds from a call site.
+extern "C-unwind" fn trampoline() -> ! {
+    match EXCEPTION.take() {
+        Some(exception) => panic!("floating point exception: {exception}"),
+        None => panic!("floating point exception"),
+    }
+}
+
+// SAFETY: This is a signal handler. It only touches the interrupted context, its stack, and
+// const-initialized thread locals, or chains to the previous handler.
+extern "C" fn sigtrap(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    unsafe {
+        let Some(masks) = STEPPING.get() else {
+            chain(signal, info, context);
+            return;
+        };
+
+        let context = context.cast();
+        if let Some(step) = UNWINDING.get() {
+            if !redirect(context, step) {
+                if step.steps >= MAX_STEPS {
+                    abort(context, masks, step.exception);
+                }
+
+                // Keep stepping with exceptions masked.
+                UNWINDING.set(Some(Step {
+                    ip: x86_64::ip_in_context(context),
+                    steps: step.steps + 1,
+                    ..step
+                }));
+                return;
+            }
+
+            UNWINDING.set(None);
+            EXCEPTION.set(Some(step.exception));
+        }
+
+        STEPPING.set(None);
+        x86_64::restore_in_context(context, masks);
+        x86_64::single_step_in_context(context, false);
+    }
+}
+
+/// Give up on a panic that did not reach a call or return, and report the exception like
+/// [`Action::Abort`](crate::Action::Abort). The thread has not called or returned since the
+/// exception, so the backtrace still points at the function that raised it.
+///
+/// # Safety
+///
+/// Must only be called by the `SIGTRAP` signal handler. `context` must be the `ucontext_t` pointer
+/// passed to the signal handler.
+unsafe fn abort(context: *mut libc::ucontext_t, masks: Masks, exception: Exception) -> ! {
+    use std::fmt::Write as _;
+
+    UNWINDING.set(None);
+    STEPPING.set(None);
+    x86_64::restore_in_context(context, masks);
+    x86_64::single_step_in_context(context, false);
+
+    let mut buffer = StackBuffer::<160>::new();
+    let _ = writeln!(
+        buffer,
+        "batman: floating point exception ({exception}) did not reach a call or return within \
+        {MAX_STEPS} instructions; aborting instead of panicking"
+    );
+    // SAFETY: `write` is async-signal-safe.
+    let bytes = buffer.as_bytes();
+    libc::write(2, bytes.as_ptr().cast(), bytes.len());
+
+    handler::report(context)
+}
+
+/// Call the previous `SIGTRAP` handler, or perform the default action.
+unsafe fn chain(signal: libc::c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let Some(previous) = PREVIOUS.get() else {
+        return;
+    };
+
+    match previous.sa_sigaction {
+        libc::SIG_IGN => (),
+        libc::SIG_DFL => {
+            // Restore the default disposition and raise the signal again. It is delivered as soon
+            // as this handler returns and unblocks it.
+            libc::sigaction(signal, previous, ptr::null_mut());
+            libc::raise(signal);
+        }
+        action if previous.sa_flags & libc::SA_SIGINFO != 0 => {
+            let action: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut c_void) =
+                mem::transmute(action);
+            action(signal, info, context);
+        }
+        action => {
+            let action: extern "C" fn(libc::c_int) = mem::transmute(action);
+            action(signal);
+        }
+    }
+}
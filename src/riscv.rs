@@ -0,0 +1,95 @@
+//! RISC-V does not support trapping floating point exceptions.
+//! See: The RISC-V Instruction Set Manual, Volume I: Unprivileged ISA:
+//!
+//! # 11.2 Floating-Point Control and Status Register
+//!
+//! As allowed by the standard, we do not support traps on floating-point exceptions in the F
+//! extension, but instead require explicit checks of the flags in software.
+//!
+//! ----
+//!
+//! The accrued exception flags in `fflags` are polled with [`crate::checkpoint`].
+
+use crate::backend::{FpuBackend, Saved};
+use crate::policy::Exceptions;
+use core::arch::asm;
+
+/// Inexact (fflags)
+const NX: u32 = 1 << 0;
+/// Underflow (fflags)
+const UF: u32 = 1 << 1;
+/// Overflow (fflags)
+const OF: u32 = 1 << 2;
+/// Divide by Zero (fflags)
+const DZ: u32 = 1 << 3;
+/// Invalid Operation (fflags)
+const NV: u32 = 1 << 4;
+
+/// Convert the accrued exception flags to exceptions.
+///
+/// The flags are in the reverse order of [`Exceptions`], and there is no denormal operand flag.
+fn from_fflags(fflags: u32) -> Exceptions {
+    let mut bits = 0;
+    for (bit, flag) in [(0, NV), (2, DZ), (3, OF), (4, UF), (5, NX)] {
+        if fflags & flag != 0 {
+            bits |= 1 << bit;
+        }
+    }
+    Exceptions::from_bits(bits)
+}
+
+/// The accrued exception flags of the F extension.
+pub(crate) struct Fflags;
+
+impl FpuBackend for Fflags {
+    fn name(&self) -> &'static str {
+        "fflags"
+    }
+
+    fn is_supported(&self) -> bool {
+        false
+    }
+
+    unsafe fn enable(&self, _exceptions: Exceptions) {
+        self.clear_status();
+    }
+
+    unsafe fn disable(&self, _exceptions: Exceptions) {}
+
+    fn read_enabled(&self) -> Exceptions {
+        Exceptions::empty()
+    }
+
+    fn save(&self) -> Saved {
+        Saved(u64::from(frflags()))
+    }
+
+    unsafe fn restore(&self, saved: Saved) {
+        fsflags(saved.0 as u32);
+    }
+
+    fn read_status(&self) -> Exceptions {
+        from_fflags(frflags())
+    }
+
+    unsafe fn clear_status(&self) {
+        fsflags(0);
+    }
+}
+
+/// Read the accrued exception flags.
+fn frflags() -> u32 {
+    let fflags: usize;
+    // SAFETY: Reads a CSR.
+    unsafe { asm!("frflags {}", out(reg) fflags, options(nomem, nostack, preserves_flags)) };
+    fflags as u32
+}
+
+/// Write the accrued exception flags.
+///
+/// # Safety
+///
+/// Changes the floating point environment of the current thread.
+unsafe fn fsflags(fflags: u32) {
+    asm!("fsflags {}", in(reg) fflags as usize, options(nomem, nostack, preserves_flags));
+}
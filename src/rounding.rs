@@ -0,0 +1,88 @@
+//! Scoped control of the rounding mode.
+
+/// The direction that inexact results are rounded, as defined by IEEE 754. See [`with_rounding`].
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum RoundingMode {
+    /// Round to the nearest representable number, and to even on ties. This is the default.
+    #[default]
+    ToNearest,
+    /// Round toward negative infinity.
+    TowardNegative,
+    /// Round toward positive infinity.
+    TowardPositive,
+    /// Round toward zero (truncate).
+    TowardZero,
+}
+
+impl RoundingMode {
+    /// The x86 rounding control field. SSE and x87 use the same encoding.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    const fn rc(self) -> u8 {
+        match self {
+            Self::ToNearest => 0b00,
+            Self::TowardNegative => 0b01,
+            Self::TowardPositive => 0b10,
+            Self::TowardZero => 0b11,
+        }
+    }
+}
+
+/// Run a closure with a rounding mode on the current thread.
+///
+/// Both the SSE and x87 rounding modes are set, and the previous modes are restored afterward,
+/// even if `f` panics. Calls can be nested. This is intended for code that needs directed
+/// rounding, like interval arithmetic.
+///
+/// ```
+/// use batman::RoundingMode;
+/// use std::hint::black_box;
+///
+/// let lower = batman::with_rounding(RoundingMode::TowardNegative, || {
+///     black_box(1.0_f64) / black_box(3.0)
+/// });
+/// let upper = batman::with_rounding(RoundingMode::TowardPositive, || {
+///     black_box(1.0_f64) / black_box(3.0)
+/// });
+/// # #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+/// assert!(lower < upper);
+/// ```
+///
+/// The compiler assumes the default rounding mode when it evaluates constant expressions, so
+/// operands that are known at compile time must be hidden with [`black_box`](std::hint::black_box)
+/// or similar.
+///
+/// Code inside `f` must use `with_rounding` to change the rounding mode instead of writing the
+/// control registers directly. When debug assertions are enabled, the mode that was given is
+/// expected by [`verify`](crate::verify) and [`start_sampler`](crate::start_sampler) on armed
+/// threads, so they report a direct change while `f` is running. `with_rounding` also calls
+/// `verify` when `f` returns. On threads that are not armed, only the return is checked: this
+/// function panics if `f` returns with a different rounding mode, and a change that `f` reverts
+/// before it returns goes unnoticed.
+///
+/// Unlike the rest of `batman`, this function also works when debug assertions are disabled. It
+/// only calls `f` on platforms other than `x86` and `x86_64`.
+#[track_caller]
+pub fn with_rounding<F, R>(mode: RoundingMode, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        let guard = crate::x86_64::RoundingGuard::new(mode.rc());
+        let result = f();
+        crate::verify();
+        debug_assert!(
+            guard.is_unchanged(),
+            "rounding mode changed inside `with_rounding({mode:?})`",
+        );
+
+        result
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        let _ = mode;
+        f()
+    }
+}
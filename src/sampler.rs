@@ -0,0 +1,123 @@
+//! Poll the status flags and the floating point environment of every armed thread in the
+//! background.
+//!
+//! The sampler thread sends a private real-time signal to each thread with `tgkill`. The signal
+//! handler runs on the receiving thread and reads the status flags (when exceptions are polled)
+//! and the environment (on `x86` and `x86_64`) of the interrupted context. On Linux `x86` and
+//! `x86_64`, the kernel resets the FPU for the handler, so the live registers would not show
+//! them.
+
+use crate::policy::{self, Exceptions};
+use crate::{backend, handler, ARMED, LAST_CHECKPOINT};
+use log::debug;
+use std::fmt::Write as _;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+use std::{ffi::c_void, fs, io, thread};
+
+// Set once the sampler thread is running.
+static STARTED: Mutex<bool> = Mutex::new(false);
+
+/// See [`crate::start_sampler`].
+pub(crate) unsafe fn start(period: Duration) -> io::Result<()> {
+    let mut started = STARTED.lock().unwrap_or_else(PoisonError::into_inner);
+    if *started {
+        return Ok(());
+    }
+
+    let signal = handler::claim_realtime_signal(handler)?;
+    debug!("Using signal {signal} to sample the floating point status flags");
+
+    // The sampler is not armed, because it is spawned with the standard library.
+    handler::spawn_internal("batman-sampler", move || loop {
+        thread::sleep(period);
+
+        if let Err(err) = sample(signal) {
+            debug!("Stopping the sampler: {err}");
+            break;
+        }
+    })?;
+
+    *started = true;
+
+    Ok(())
+}
+
+/// Send the signal to every thread in the process, except `batman`'s own threads.
+fn sample(signal: libc::c_int) -> io::Result<()> {
+    // SAFETY: These system calls have no preconditions.
+    let (pid, this) = unsafe { (libc::getpid(), libc::gettid()) };
+    let internal = handler::internal_threads();
+
+    for entry in fs::read_dir("/proc/self/task")? {
+        let Some(tid) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<libc::pid_t>().ok())
+        else {
+            continue;
+        };
+        if tid == this || internal.contains(&tid) {
+            continue;
+        }
+
+        // SAFETY: The signal was claimed by `start`. The thread may have exited since the
+        // directory was read, so errors are ignored.
+        unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, signal) };
+    }
+
+    Ok(())
+}
+
+// SAFETY: This is a signal handler. It only reads the interrupted context and const-initialized
+// thread locals, and formats into a buffer on the stack.
+extern "C" fn handler(_signal: libc::c_int, _info: *mut libc::siginfo_t, context: *mut c_void) {
+    debug_assert!(backend::is_selected());
+
+    if !ARMED.get() {
+        return;
+    }
+
+    let mut buffer = policy::StackBuffer::<512>::new();
+    let raised = if backend::is_polling() {
+        unsafe { backend::status_in_context(context.cast(), policy::enabled()) }
+    } else {
+        Exceptions::empty()
+    };
+    if !raised.is_empty() {
+        let _ = write!(buffer, "batman: floating point exception (");
+        for (i, exception) in raised.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ", " };
+            let _ = write!(buffer, "{separator}{exception}");
+        }
+        let _ = write!(buffer, ") occurred");
+    } else {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(changes) = unsafe { crate::watchdog::verify_in_context(context.cast()) } {
+            let _ = write!(
+                buffer,
+                "batman: floating point environment changed ({changes})"
+            );
+        }
+    }
+    if buffer.as_bytes().is_empty() {
+        return;
+    }
+
+    let _ = match LAST_CHECKPOINT.get() {
+        Some(location) => writeln!(buffer, " after the checkpoint at {location}"),
+        None => writeln!(buffer, " before the first checkpoint"),
+    };
+
+    // SAFETY: `write` is async-signal-safe.
+    unsafe {
+        libc::write(
+            2,
+            buffer.as_bytes().as_ptr().cast(),
+            buffer.as_bytes().len(),
+        )
+    };
+
+    // The handler is installed on armed threads, and it terminates the process.
+    handler::raise();
+}
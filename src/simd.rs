@@ -0,0 +1,434 @@
+//! Lane-level reports for faulting SSE and AVX arithmetic instructions.
+//!
+//! Packed instructions raise an exception if any lane raises it, and the exception flags do not
+//! say which lane did. The tracer thread decodes the faulting instruction, reads its operands
+//! from the saved registers (or memory), and evaluates each lane again with exceptions masked.
+//!
+//! Auto-vectorized loops often process a few more lanes than the program reads, e.g. a tail that
+//! is padded with zeros. Faults in those lanes are flagged as probable padding.
+
+use crate::policy::Exceptions;
+use crate::x86_64::{evaluate_f32, evaluate_f64, Operation, Registers, REG_IP};
+use std::fmt;
+
+/// The longest x86 instruction.
+const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// A decoded SSE or AVX arithmetic instruction.
+struct Instruction {
+    operation: Operation,
+    /// `true` for VEX-encoded (AVX) instructions.
+    vex: bool,
+    /// `true` for 256-bit instructions.
+    wide: bool,
+    /// `true` for `f64` lanes.
+    double: bool,
+    /// `true` for instructions that only operate on the lowest lane.
+    scalar: bool,
+    /// Destination register.
+    reg: usize,
+    /// First source register for VEX-encoded instructions.
+    vvvv: usize,
+    /// Second source operand.
+    rm: Operand,
+}
+
+enum Operand {
+    Register(usize),
+    Memory(u64),
+}
+
+/// One lane of a faulting instruction.
+struct Lane {
+    a: f64,
+    b: f64,
+    result: f64,
+    /// The unmasked exceptions raised by this lane.
+    raised: Exceptions,
+}
+
+/// A lane-level report for the faulting instruction.
+pub(crate) struct Report {
+    mnemonic: String,
+    operands: String,
+    operation: Operation,
+    lanes: Vec<Lane>,
+}
+
+/// Describe the lanes of the faulting instruction, if it is a supported SSE or AVX arithmetic
+/// instruction.
+///
+/// This must be called by the tracer thread (or the helper process) while the faulting thread is
+/// stopped. `pid` is the process that raised the exception.
+pub(crate) fn describe(registers: &Registers, pid: libc::pid_t) -> Option<Report> {
+    let ip = gpr(registers, REG_IP);
+    let mut bytes = [0; MAX_INSTRUCTION_LEN];
+    // The instruction may end just before an unmapped page.
+    let len = (MAX_INSTRUCTION_LEN as u64).min(4096 - ip % 4096) as usize;
+    if !read_memory(pid, ip, &mut bytes[..len]) {
+        return None;
+    }
+    let instruction = decode(&bytes[..len], registers)?;
+
+    let size = if instruction.double { 8 } else { 4 };
+    let count = match (instruction.scalar, instruction.wide) {
+        (true, _) => 1,
+        (false, false) => 16 / size,
+        (false, true) => 32 / size,
+    };
+
+    let a = if instruction.vex {
+        registers.ymm(instruction.vvvv)?
+    } else {
+        registers.ymm(instruction.reg)?
+    };
+    let b = match instruction.rm {
+        Operand::Register(index) => registers.ymm(index)?,
+        Operand::Memory(address) => {
+            let mut bytes = [0; 32];
+            if !read_memory(pid, address, &mut bytes[..count * size]) {
+                return None;
+            }
+            bytes
+        }
+    };
+
+    let unmasked = Exceptions::from_bits(!(registers.mxcsr >> 7) as u8);
+    let lanes = (0..count)
+        .map(|i| {
+            let (a, b, result, raised) = if instruction.double {
+                let a = f64::from_le_bytes(a[i * 8..][..8].try_into().unwrap());
+                let b = f64::from_le_bytes(b[i * 8..][..8].try_into().unwrap());
+                let (result, raised) = evaluate_f64(instruction.operation, a, b, registers.mxcsr);
+                (a, b, result, raised)
+            } else {
+                let a = f32::from_le_bytes(a[i * 4..][..4].try_into().unwrap());
+                let b = f32::from_le_bytes(b[i * 4..][..4].try_into().unwrap());
+                let (result, raised) = evaluate_f32(instruction.operation, a, b, registers.mxcsr);
+                (a.into(), b.into(), result.into(), raised)
+            };
+
+            Lane {
+                a,
+                b,
+                result,
+                raised: Exceptions::from_bits(raised.bits() & unmasked.bits()),
+            }
+        })
+        .collect();
+
+    let name = match instruction.operation {
+        Operation::Add => "add",
+        Operation::Sub => "sub",
+        Operation::Mul => "mul",
+        Operation::Div => "div",
+        Operation::Sqrt => "sqrt",
+        Operation::Min => "min",
+        Operation::Max => "max",
+    };
+    let suffix = match (instruction.scalar, instruction.double) {
+        (false, false) => "ps",
+        (false, true) => "pd",
+        (true, false) => "ss",
+        (true, true) => "sd",
+    };
+    let register = if instruction.wide { "ymm" } else { "xmm" };
+    let rm = match instruction.rm {
+        Operand::Register(index) => format!("{register}{index}"),
+        Operand::Memory(address) => format!("[{address:#x}]"),
+    };
+    let operands = if instruction.vex {
+        format!(
+            "{register}{}, {register}{}, {rm}",
+            instruction.reg, instruction.vvvv
+        )
+    } else {
+        format!("{register}{}, {rm}", instruction.reg)
+    };
+
+    Some(Report {
+        mnemonic: format!("{}{name}{suffix}", if instruction.vex { "v" } else { "" }),
+        operands,
+        operation: instruction.operation,
+        lanes,
+    })
+}
+
+impl Report {
+    /// Faulting lanes are probably padding if they are all at the end of the register, and each
+    /// of them has a zero operand.
+    fn is_padding(&self) -> bool {
+        let Some(first) = self.lanes.iter().position(|lane| !lane.raised.is_empty()) else {
+            return false;
+        };
+
+        first > 0
+            && self.lanes[first..].iter().all(|lane| {
+                !lane.raised.is_empty()
+                    && (lane.b == 0.0 || (self.operation != Operation::Sqrt && lane.a == 0.0))
+            })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Faulting instruction: {} {}",
+            self.mnemonic, self.operands
+        )?;
+
+        for (i, lane) in self.lanes.iter().enumerate() {
+            let marker = if lane.raised.is_empty() { ' ' } else { '*' };
+            write!(f, "  {marker} lane {i}: ")?;
+            match self.operation {
+                Operation::Sqrt => write!(f, "sqrt({:?})", lane.b)?,
+                Operation::Min => write!(f, "min({:?}, {:?})", lane.a, lane.b)?,
+                Operation::Max => write!(f, "max({:?}, {:?})", lane.a, lane.b)?,
+                Operation::Add => write!(f, "{:?} + {:?}", lane.a, lane.b)?,
+                Operation::Sub => write!(f, "{:?} - {:?}", lane.a, lane.b)?,
+                Operation::Mul => write!(f, "{:?} * {:?}", lane.a, lane.b)?,
+                Operation::Div => write!(f, "{:?} / {:?}", lane.a, lane.b)?,
+            }
+            write!(f, " = {:?}", lane.result)?;
+            for (j, exception) in lane.raised.iter().enumerate() {
+                f.write_str(if j == 0 { " (" } else { ", " })?;
+                write!(f, "{exception}")?;
+            }
+            if !lane.raised.is_empty() {
+                f.write_str(")")?;
+            }
+            writeln!(f)?;
+        }
+
+        if self.is_padding() {
+            writeln!(
+                f,
+                "note: The faulting lanes are at the end of the register and have zero operands. \
+                They are probably padding or an unused tail of a vectorized loop."
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode a supported SSE or AVX arithmetic instruction.
+fn decode(bytes: &[u8], registers: &Registers) -> Option<Instruction> {
+    let mut i = 0;
+    let mut prefix = 0;
+
+    // Legacy prefixes. Only the operand size and repeat prefixes select the instruction.
+    while let Some(&byte) = bytes.get(i) {
+        match byte {
+            0x66 | 0xf2 | 0xf3 => prefix = byte,
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 => (),
+            _ => break,
+        }
+        i += 1;
+    }
+
+    let (mut r, mut x, mut b) = (0, 0, 0);
+    let mut vex = false;
+    let mut wide = false;
+    let mut vvvv = 0;
+    match *bytes.get(i)? {
+        #[cfg(target_arch = "x86_64")]
+        rex @ 0x40..=0x4f => {
+            r = usize::from(rex >> 2 & 1) << 3;
+            x = usize::from(rex >> 1 & 1) << 3;
+            b = usize::from(rex & 1) << 3;
+            i += 1;
+            if *bytes.get(i)? != 0x0f {
+                return None;
+            }
+            i += 1;
+        }
+        0x0f => i += 1,
+        0xc5 => {
+            let byte = *bytes.get(i + 1)?;
+            if !is_vex(byte) {
+                return None;
+            }
+            r = usize::from(!byte >> 7 & 1) << 3;
+            vvvv = usize::from(!byte >> 3 & 0xf);
+            wide = byte & 0x4 != 0;
+            prefix = [0, 0x66, 0xf3, 0xf2][usize::from(byte & 0x3)];
+            vex = true;
+            i += 2;
+        }
+        0xc4 => {
+            let byte = *bytes.get(i + 1)?;
+            if !is_vex(byte) {
+                return None;
+            }
+            r = usize::from(!byte >> 7 & 1) << 3;
+            x = usize::from(!byte >> 6 & 1) << 3;
+            b = usize::from(!byte >> 5 & 1) << 3;
+            // Only the 0F opcode map.
+            if byte & 0x1f != 1 {
+                return None;
+            }
+            let byte = *bytes.get(i + 2)?;
+            vvvv = usize::from(!byte >> 3 & 0xf);
+            wide = byte & 0x4 != 0;
+            prefix = [0, 0x66, 0xf3, 0xf2][usize::from(byte & 0x3)];
+            vex = true;
+            i += 3;
+        }
+        _ => return None,
+    }
+
+    // The extended registers are not available in 32-bit mode, and the extra register bits are
+    // ignored.
+    #[cfg(target_arch = "x86")]
+    {
+        (r, x, b, vvvv) = (0, 0, 0, vvvv & 0x7);
+    }
+
+    let operation = match *bytes.get(i)? {
+        0x51 => Operation::Sqrt,
+        0x58 => Operation::Add,
+        0x59 => Operation::Mul,
+        0x5c => Operation::Sub,
+        0x5d => Operation::Min,
+        0x5e => Operation::Div,
+        0x5f => Operation::Max,
+        _ => return None,
+    };
+    i += 1;
+
+    let (double, scalar) = match prefix {
+        0x66 => (true, false),
+        0xf3 => (false, true),
+        0xf2 => (true, true),
+        _ => (false, false),
+    };
+
+    let modrm = *bytes.get(i)?;
+    i += 1;
+    let mode = modrm >> 6;
+    let reg = usize::from(modrm >> 3 & 0x7) | r;
+    let rm = usize::from(modrm & 0x7);
+
+    let rm = if mode == 3 {
+        Operand::Register(rm | b)
+    } else {
+        let mut rip_relative = false;
+        let mut address = 0_u64;
+        let mut disp_len = match mode {
+            1 => 1,
+            2 => 4,
+            _ => 0,
+        };
+
+        if rm == 4 {
+            let sib = *bytes.get(i)?;
+            i += 1;
+            let scale = 1 << (sib >> 6);
+            let index = usize::from(sib >> 3 & 0x7) | x;
+            let base = usize::from(sib & 0x7);
+            if index != 4 {
+                address = gpr(registers, GREGS[index]).wrapping_mul(scale);
+            }
+            if base == 5 && mode == 0 {
+                disp_len = 4;
+            } else {
+                address = address.wrapping_add(gpr(registers, GREGS[base | b]));
+            }
+        } else if rm == 5 && mode == 0 {
+            // This is an absolute address in 32-bit mode.
+            rip_relative = cfg!(target_arch = "x86_64");
+            disp_len = 4;
+        } else {
+            address = gpr(registers, GREGS[rm | b]);
+        }
+
+        let disp = match disp_len {
+            1 => i64::from(*bytes.get(i)? as i8),
+            4 => i64::from(i32::from_le_bytes(bytes.get(i..i + 4)?.try_into().ok()?)),
+            _ => 0,
+        };
+        i += disp_len;
+
+        // RIP-relative addresses are relative to the next instruction. None of the supported
+        // instructions have an immediate operand.
+        if rip_relative {
+            address = gpr(registers, REG_IP) + i as u64;
+        }
+
+        // Addresses wrap around in 32-bit mode.
+        Operand::Memory(address.wrapping_add(disp as u64) as usize as u64)
+    };
+
+    Some(Instruction {
+        operation,
+        vex,
+        wide: wide && !scalar,
+        double,
+        scalar,
+        reg,
+        vvvv,
+        rm,
+    })
+}
+
+/// `C4` and `C5` are `LES` and `LDS` in 32-bit mode, unless the next byte would be an invalid
+/// ModRM for them. VEX prefixes always set those bits in 32-bit mode.
+fn is_vex(byte: u8) -> bool {
+    cfg!(target_arch = "x86_64") || byte >> 6 == 0x3
+}
+
+/// The general purpose registers in `mcontext_t::gregs`, in encoding order.
+#[cfg(target_arch = "x86")]
+const GREGS: [libc::c_int; 8] = [
+    libc::REG_EAX,
+    libc::REG_ECX,
+    libc::REG_EDX,
+    libc::REG_EBX,
+    libc::REG_ESP,
+    libc::REG_EBP,
+    libc::REG_ESI,
+    libc::REG_EDI,
+];
+#[cfg(target_arch = "x86_64")]
+const GREGS: [libc::c_int; 16] = [
+    libc::REG_RAX,
+    libc::REG_RCX,
+    libc::REG_RDX,
+    libc::REG_RBX,
+    libc::REG_RSP,
+    libc::REG_RBP,
+    libc::REG_RSI,
+    libc::REG_RDI,
+    libc::REG_R8,
+    libc::REG_R9,
+    libc::REG_R10,
+    libc::REG_R11,
+    libc::REG_R12,
+    libc::REG_R13,
+    libc::REG_R14,
+    libc::REG_R15,
+];
+
+/// Read a general purpose register, zero-extended to 64 bits.
+fn gpr(registers: &Registers, reg: libc::c_int) -> u64 {
+    registers.gregs[reg as usize] as usize as u64
+}
+
+/// Read memory from a process without faulting on unmapped addresses.
+fn read_memory(pid: libc::pid_t, address: u64, buffer: &mut [u8]) -> bool {
+    let local = libc::iovec {
+        iov_base: buffer.as_mut_ptr().cast(),
+        iov_len: buffer.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut _,
+        iov_len: buffer.len(),
+    };
+
+    // SAFETY: The kernel checks that the remote memory is readable.
+    let read = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+
+    read == buffer.len() as isize
+}
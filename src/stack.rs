@@ -14,14 +14,88 @@ const RUST_BACKTRACE_SENTINEL: &str = "__rust_begin_short_backtrace";
 pub(crate) fn print(frames: Vec<Frame>) {
     eprintln!("\nFloating point exception occurred.");
 
-    let (frames, note) = match std::env::var("RUST_BACKTRACE").as_deref() {
-        Ok("full") => (frames, false),
+    let Some((frames, note)) = select(frames, true, resolve) else {
+        return;
+    };
+
+    let frames = frames
+        .into_iter()
+        .map(BacktraceFrame::from)
+        .collect::<Vec<_>>();
+    let mut trace = Backtrace::from(frames);
+    trace.resolve();
+
+    eprintln!("{trace:?}");
+    if note {
+        print_note();
+    }
+}
+
+/// Print a backtrace of instruction addresses, like [`print`]. `translate` maps each address to
+/// the same instruction in this process, when they were captured by another process.
+/// `in_handler` is `true` when the backtrace starts in the signal handler, instead of at the
+/// faulting instruction.
+#[cfg(target_os = "linux")]
+pub(crate) fn print_addresses(
+    addresses: Vec<usize>,
+    in_handler: bool,
+    translate: &dyn Fn(usize) -> Option<usize>,
+) {
+    eprintln!("\nFloating point exception occurred.");
+
+    let Some((addresses, note)) = select(addresses, in_handler, |&address, predicate| {
+        translate(address).is_none_or(|local| resolve_address(local, predicate))
+    }) else {
+        return;
+    };
+
+    for (i, &address) in addresses.iter().enumerate() {
+        let mut resolved = false;
+        if let Some(local) = translate(address) {
+            // Like `backtrace::resolve_frame`, point inside the call instead of after it.
+            backtrace::resolve(local.saturating_sub(1) as *mut _, |symbol| {
+                // Inlined functions share the frame number.
+                let prefix = match resolved {
+                    false => format!("{i:4}: "),
+                    true => " ".repeat(6),
+                };
+                match symbol.name() {
+                    Some(name) => eprintln!("{prefix}{name:#}"),
+                    None => eprintln!("{prefix}<unknown>"),
+                }
+                print_location(symbol);
+                resolved = true;
+            });
+        }
+        if !resolved {
+            eprintln!("{i:4}: {address:#x} - <unknown>");
+        }
+    }
+    if note {
+        print_note();
+    }
+}
+
+/// Select the frames to print, like the standard library does for `RUST_BACKTRACE`. Returns the
+/// frames, and whether any were omitted, or `None` if backtraces are disabled. The frames of the
+/// signal handler are omitted when `in_handler` is `true`.
+fn select<T, R>(frames: Vec<T>, in_handler: bool, resolve: R) -> Option<(Vec<T>, bool)>
+where
+    R: Fn(&T, fn(&str) -> bool) -> bool,
+{
+    match std::env::var("RUST_BACKTRACE").as_deref() {
+        Ok("full") => Some((frames, false)),
         Ok(_) => {
             let mut iter = frames.iter().enumerate();
-            let start = iter
-                .find_map(|(i, frame)| resolve(frame, |name| name == BATMAN_SENTINEL).then_some(i))
-                .map(|index| index + 1)
-                .unwrap_or_default();
+            let start = match in_handler {
+                true => iter
+                    .find_map(|(i, frame)| {
+                        resolve(frame, |name| name == BATMAN_SENTINEL).then_some(i)
+                    })
+                    .map(|index| index + 1)
+                    .unwrap_or_default(),
+                false => 0,
+            };
             let end = iter
                 .find_map(|(i, frame)| {
                     resolve(frame, |name| name.contains(RUST_BACKTRACE_SENTINEL)).then_some(i)
@@ -29,35 +103,64 @@ pub(crate) fn print(frames: Vec<Frame>) {
                 .unwrap_or(usize::MAX);
             let frames = frames.into_iter().skip(start).take(end - start).collect();
 
-            (frames, true)
+            Some((frames, true))
         }
         Err(_) => {
             eprintln!(
                 "note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace."
             );
-            return;
+            None
         }
-    };
+    }
+}
 
-    let frames = frames
-        .into_iter()
-        .map(BacktraceFrame::from)
-        .collect::<Vec<_>>();
-    let mut trace = Backtrace::from(frames);
-    trace.resolve();
+fn print_note() {
+    eprintln!(
+        "note: Some details are omitted, run with `RUST_BACKTRACE=full` for a verbose backtrace."
+    );
+}
 
-    eprintln!("{trace:?}");
-    if note {
-        eprintln!(
-            "note: Some details are omitted, run with `RUST_BACKTRACE=full` for a verbose backtrace."
-        );
+/// Print the x87 instruction that raised an exception.
+///
+/// x87 exceptions are raised by the next x87 instruction after the one that caused them, so the
+/// backtrace does not point at the culprit.
+///
+/// `translate` maps the address to the same instruction in this process.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+pub(crate) fn print_x87(address: usize, opcode: u16, translate: &dyn Fn(usize) -> Option<usize>) {
+    // The opcode is the low 11 bits of the first two bytes of the instruction, excluding
+    // prefixes. The high 5 bits of the first byte are always `11011`.
+    let [high, low] = opcode.to_be_bytes();
+    eprintln!(
+        "\nThe exception was raised by the x87 instruction `{:02x} {low:02x}` at {address:#x}, and \
+        delivered by the next x87 instruction in the backtrace.",
+        0xd8 | (high & 0x7),
+    );
+
+    let Some(local) = translate(address) else {
+        return;
+    };
+    backtrace::resolve(local as *mut _, |symbol| {
+        if let Some(name) = symbol.name() {
+            eprintln!("   {name:#}");
+        }
+        print_location(symbol);
+    });
+}
+
+/// Print the source location of a symbol, if it is known.
+#[cfg(target_os = "linux")]
+fn print_location(symbol: &backtrace::Symbol) {
+    if let (Some(file), Some(line)) = (symbol.filename(), symbol.lineno()) {
+        eprint!("             at {}:{line}", file.display());
+        match symbol.colno() {
+            Some(column) => eprintln!(":{column}"),
+            None => eprintln!(),
+        }
     }
 }
 
-fn resolve<F>(frame: &Frame, predicate: F) -> bool
-where
-    F: Fn(&str) -> bool + Copy,
-{
+fn resolve(frame: &Frame, predicate: fn(&str) -> bool) -> bool {
     let mut name = None;
 
     backtrace::resolve_frame(frame, |symbol| {
@@ -69,3 +172,18 @@ where
 
     name.unwrap_or(true)
 }
+
+/// Like [`resolve`], for an instruction address in this process.
+#[cfg(target_os = "linux")]
+fn resolve_address(address: usize, predicate: fn(&str) -> bool) -> bool {
+    let mut name = None;
+
+    backtrace::resolve(address.saturating_sub(1) as *mut _, |symbol| {
+        name = symbol
+            .name()
+            .and_then(|symbol| symbol.as_str())
+            .map(predicate);
+    });
+
+    name.unwrap_or(true)
+}
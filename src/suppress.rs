@@ -0,0 +1,340 @@
+//! Suppressions for floating point exceptions raised by code that cannot be fixed.
+//!
+//! A suppression pattern is either a symbol name (`libfoo::kernel*`) or a source location
+//! (`src/kernel.c:42`). `*` matches any sequence of characters and `?` matches any single
+//! character. Symbol patterns are matched against demangled names, without the hash suffix that
+//! Rust appends. Source file patterns match any path that ends with the pattern.
+//!
+//! Patterns are resolved to address ranges in the executable and the shared libraries that are
+//! loaded when the pattern is added. The signal handler only has to look up the faulting
+//! instruction pointer in a sorted table.
+
+use gimli::{EndianSlice, RunTimeEndian};
+use log::debug;
+use object::{Object, ObjectSection, ObjectSymbol, SymbolKind};
+use std::collections::HashMap;
+use std::ffi::{c_int, c_void, CStr, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::{fs, io, ops::Range, ptr};
+
+/// Environment variable naming a suppressions file that is loaded when the handler is installed.
+pub(crate) const ENV_VAR: &str = "BATMAN_SUPPRESSIONS";
+
+// Sorted, non-overlapping address ranges that are read by the signal handler. Tables are leaked
+// when they are replaced, because the signal handler may still be reading the old one.
+static RANGES: AtomicPtr<Vec<Range<usize>>> = AtomicPtr::new(ptr::null_mut());
+
+// Serializes writers of `RANGES`.
+static WRITER: Mutex<()> = Mutex::new(());
+
+enum Pattern<'a> {
+    Symbol(&'a str),
+    Location { file: &'a str, line: u64 },
+}
+
+impl<'a> Pattern<'a> {
+    fn parse(pattern: &'a str) -> Self {
+        match pattern.rsplit_once(':') {
+            Some((file, line)) if !file.is_empty() && !file.ends_with(':') => {
+                match line.parse() {
+                    Ok(line) => Self::Location { file, line },
+                    Err(_) => Self::Symbol(pattern),
+                }
+            }
+            _ => Self::Symbol(pattern),
+        }
+    }
+}
+
+/// An executable or shared library that is loaded in the process.
+struct LoadedObject {
+    path: PathBuf,
+    bias: usize,
+}
+
+/// See [`crate::suppress`].
+pub(crate) fn suppress(patterns: &[&str]) -> io::Result<()> {
+    // Suppressed exceptions are resumed, which needs the signal context.
+    crate::handler::check_capture()?;
+
+    let patterns = patterns
+        .iter()
+        .map(|pattern| Pattern::parse(pattern))
+        .collect::<Vec<_>>();
+    let has_locations = patterns
+        .iter()
+        .any(|pattern| matches!(pattern, Pattern::Location { .. }));
+
+    let mut ranges = vec![];
+    for object in loaded_objects() {
+        // Pseudo-objects like the vDSO do not exist on the file system.
+        let Ok(data) = fs::read(&object.path) else {
+            continue;
+        };
+        let Ok(file) = object::File::parse(&*data) else {
+            continue;
+        };
+
+        symbols(&file, object.bias, &patterns, &mut ranges);
+        if has_locations {
+            if let Err(err) = locations(&file, object.bias, &patterns, &mut ranges) {
+                debug!("Unable to read debug info from {:?}: {err}", object.path);
+            }
+        }
+    }
+
+    debug!("Suppressions resolved to {} address ranges", ranges.len());
+
+    if !ranges.is_empty() {
+        // SAFETY: Suppressed exceptions resume the faulting thread.
+        unsafe { crate::resume::install()? };
+        add(ranges);
+    }
+
+    Ok(())
+}
+
+/// See [`crate::suppress_file`].
+pub(crate) fn suppress_file(path: &Path) -> io::Result<()> {
+    let contents = fs::read_to_string(path)?;
+    let patterns = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+
+    suppress(&patterns)
+}
+
+/// Check if an instruction address is suppressed.
+///
+/// This function is async-signal-safe.
+pub(crate) fn is_suppressed(address: usize) -> bool {
+    let ranges = RANGES.load(Ordering::Acquire);
+    if ranges.is_null() {
+        return false;
+    }
+
+    // SAFETY: Tables are never freed.
+    let ranges = unsafe { &*ranges };
+    let index = ranges.partition_point(|range| range.end <= address);
+
+    ranges
+        .get(index)
+        .is_some_and(|range| range.contains(&address))
+}
+
+/// Merge new ranges into the table read by the signal handler.
+fn add(new: Vec<Range<usize>>) {
+    let _guard = WRITER.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let old = RANGES.load(Ordering::Acquire);
+    let mut ranges = if old.is_null() {
+        new
+    } else {
+        // SAFETY: Tables are never freed.
+        let mut ranges = unsafe { &*old }.clone();
+        ranges.extend(new);
+        ranges
+    };
+
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    RANGES.store(Box::into_raw(Box::new(merged)), Ordering::Release);
+}
+
+/// List the executable and shared libraries that are loaded in the process.
+fn loaded_objects() -> Vec<LoadedObject> {
+    unsafe extern "C" fn callback(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut c_void,
+    ) -> c_int {
+        let objects = &mut *data.cast::<Vec<LoadedObject>>();
+        let info = &*info;
+        let name = if info.dlpi_name.is_null() {
+            &[]
+        } else {
+            CStr::from_ptr(info.dlpi_name).to_bytes()
+        };
+
+        // The executable is always first, and it does not have a name.
+        let path = match name {
+            [] if objects.is_empty() => PathBuf::from("/proc/self/exe"),
+            [] => return 0,
+            name => PathBuf::from(OsStr::from_bytes(name)),
+        };
+
+        objects.push(LoadedObject {
+            path,
+            bias: info.dlpi_addr as usize,
+        });
+
+        0
+    }
+
+    let mut objects = Vec::<LoadedObject>::new();
+    // SAFETY: The callback only accesses `objects` through the data pointer.
+    unsafe { libc::dl_iterate_phdr(Some(callback), ptr::addr_of_mut!(objects).cast()) };
+
+    objects
+}
+
+/// Find the functions with names that match a pattern.
+fn symbols(
+    file: &object::File,
+    bias: usize,
+    patterns: &[Pattern],
+    ranges: &mut Vec<Range<usize>>,
+) {
+    for symbol in file.symbols().chain(file.dynamic_symbols()) {
+        if symbol.kind() != SymbolKind::Text || symbol.size() == 0 {
+            continue;
+        }
+        let Ok(name) = symbol.name() else {
+            continue;
+        };
+
+        let name = format!("{:#}", rustc_demangle::demangle(name));
+        let matched = patterns.iter().any(|pattern| match pattern {
+            Pattern::Symbol(pattern) => glob(pattern, &name),
+            Pattern::Location { .. } => false,
+        });
+        if matched {
+            let start = bias + symbol.address() as usize;
+            ranges.push(start..start + symbol.size() as usize);
+        }
+    }
+}
+
+/// Find the instructions generated for source lines that match a pattern.
+fn locations(
+    file: &object::File,
+    bias: usize,
+    patterns: &[Pattern],
+    ranges: &mut Vec<Range<usize>>,
+) -> Result<(), gimli::Error> {
+    let endian = if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let dwarf = gimli::Dwarf::load(|id| -> Result<_, gimli::Error> {
+        let data = file
+            .section_by_name(id.name())
+            .and_then(|section| section.data().ok())
+            .unwrap_or_default();
+
+        Ok(EndianSlice::new(data, endian))
+    })?;
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let Some(program) = unit.line_program.clone() else {
+            continue;
+        };
+
+        // Cache the file names that match, by index.
+        let mut files = HashMap::new();
+        let mut start = None;
+        let mut rows = program.rows();
+        while let Some((header, row)) = rows.next_row()? {
+            let address = bias + row.address() as usize;
+            if let Some(start) = start.take() {
+                ranges.push(start..address);
+            }
+            if row.end_sequence() {
+                continue;
+            }
+
+            let Some(line) = row.line() else {
+                continue;
+            };
+            let mut candidates = patterns.iter().filter_map(|pattern| match pattern {
+                Pattern::Location { file, line: l } if *l == line.get() => Some(*file),
+                _ => None,
+            });
+            let Some(first) = candidates.next() else {
+                continue;
+            };
+
+            let path = files.entry(row.file_index()).or_insert_with(|| {
+                let entry = row.file(header)?;
+                let mut path = String::new();
+                if let Some(dir) = entry.directory(header) {
+                    let dir = dwarf.attr_string(&unit, dir).ok()?;
+                    path.push_str(&dir.to_string_lossy());
+                    path.push('/');
+                }
+                let name = dwarf.attr_string(&unit, entry.path_name()).ok()?;
+                path.push_str(&name.to_string_lossy());
+
+                Some(path)
+            });
+            let Some(path) = path else {
+                continue;
+            };
+
+            if std::iter::once(first)
+                .chain(candidates)
+                .any(|pattern| path_matches(pattern, path))
+            {
+                start = Some(address);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check if a path ends with a pattern, on a path component boundary.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    glob(pattern, path)
+        || path
+            .match_indices('/')
+            .any(|(i, _)| glob(pattern, &path[i + 1..]))
+}
+
+/// Match a string against a pattern with `*` and `?` wildcards.
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    // Backtracking matcher; `star` remembers the most recent `*` and the text position it is
+    // currently matching up to.
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
@@ -0,0 +1,80 @@
+//! Spawn threads with floating point exceptions enabled.
+//!
+//! Threads inherit the floating point environment from their parent, so the only way to enable
+//! exceptions on a worker thread with [`signal`](crate::signal) is to call it as the first thing
+//! in the thread's closure, or to call it on the parent before spawning (which also enables
+//! exceptions on the parent). The functions in this module do the former for you.
+//!
+//! ```no_run
+//! use batman::thread::BuilderExt as _;
+//! use std::thread::Builder;
+//!
+//! fn main() -> std::io::Result<()> {
+//!     // Here be dragons!
+//!     let worker = unsafe { Builder::new().name("worker".into()).spawn_trapped(|| 2.0 * 21.0)? };
+//!
+//!     assert_eq!(worker.join().unwrap(), 42.0);
+//!
+//!     Ok(())
+//! }
+//! ```
+
+use std::io;
+use std::thread::{Builder, JoinHandle};
+
+/// Spawn a new thread with floating point exceptions enabled, returning a [`JoinHandle`] for it.
+///
+/// This is shorthand for `Builder::new().spawn_trapped(f)`. See [`BuilderExt::spawn_trapped`].
+///
+/// # Safety
+///
+/// See [`signal`](crate::signal).
+pub unsafe fn spawn<F, T>(f: F) -> io::Result<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn_trapped(f)
+}
+
+/// Extension trait for [`std::thread::Builder`].
+pub trait BuilderExt {
+    /// Spawn a new thread with floating point exceptions enabled, returning a [`JoinHandle`] for
+    /// it.
+    ///
+    /// The signal handler is installed on the calling thread before spawning, so any error is
+    /// reported here instead of on the new thread. Exceptions are enabled on the new thread
+    /// before `f` runs. The FPU configuration of the calling thread is not changed.
+    ///
+    /// This is equivalent to [`Builder::spawn`] when debug assertions are disabled.
+    ///
+    /// # Safety
+    ///
+    /// See [`signal`](crate::signal).
+    unsafe fn spawn_trapped<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+}
+
+impl BuilderExt for Builder {
+    unsafe fn spawn_trapped<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        #[cfg(debug_assertions)]
+        crate::handler::install()?;
+
+        self.spawn(move || {
+            // SAFETY: The handler was installed by the parent thread, and the caller upholds the
+            // remaining invariants.
+            #[cfg(debug_assertions)]
+            unsafe {
+                crate::arm()
+            };
+
+            f()
+        })
+    }
+}
@@ -0,0 +1,154 @@
+//! Detect other code changing the floating point environment of an armed thread.
+//!
+//! The environment is saved when a thread is armed, and compared with the live environment by
+//! [`crate::verify`], or with the interrupted context by the sampler. Only the bits that `batman`
+//! and the standard library rely on are compared; the exception flags and the x87 condition codes
+//! change all the time.
+//!
+//! `batman` changes the environment on purpose in a few places. Threads are not checked while
+//! [`crate::unguarded`] masks exceptions, and [`allow_change`] updates the saved environment for
+//! the denormal and rounding modes.
+
+use crate::fenv::{FpEnv, Mxcsr, X87Control};
+use std::cell::Cell;
+use std::fmt;
+
+/// The MXCSR bits that are compared.
+const MXCSR_WATCHED: Mxcsr = Mxcsr::MASKS
+    .union(Mxcsr::DAZ)
+    .union(Mxcsr::FTZ)
+    .union(Mxcsr::RC);
+
+/// The x87 control word bits that are compared.
+const FCW_WATCHED: X87Control = X87Control::MASKS
+    .union(X87Control::PC)
+    .union(X87Control::RC);
+
+thread_local! {
+    // The environment that the thread was armed with, or `None` while it is being changed.
+    // This is read by the sampler's signal handler, so it must be const-initialized and must not
+    // need a destructor.
+    static ARMED_ENV: Cell<Option<FpEnv>> = const { Cell::new(None) };
+}
+
+/// Save the live environment of the current thread after it is armed.
+pub(crate) fn arm() {
+    ARMED_ENV.set(Some(FpEnv::save()));
+}
+
+/// Save the environment of the interrupted thread after it is armed in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn arm_in_context(context: *mut libc::ucontext_t) {
+    ARMED_ENV.set(crate::x86_64::fenv_in_context(context));
+}
+
+/// Compare the live environment of the current thread with the armed environment.
+pub(crate) fn verify() -> Option<Changes> {
+    Changes::between(ARMED_ENV.get()?, FpEnv::save())
+}
+
+/// Compare the environment of the interrupted thread with its armed environment.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler on the
+/// interrupted thread.
+#[cfg(target_os = "linux")]
+pub(crate) unsafe fn verify_in_context(context: *mut libc::ucontext_t) -> Option<Changes> {
+    // The faulting instruction is single-stepped with exceptions masked.
+    if crate::resume::is_stepping() {
+        return None;
+    }
+
+    Changes::between(ARMED_ENV.get()?, crate::x86_64::fenv_in_context(context)?)
+}
+
+/// Restore the armed environment on the current thread, so that the changes do not affect the
+/// report.
+pub(crate) fn restore() {
+    if let Some(env) = ARMED_ENV.get() {
+        // SAFETY: The thread was armed with this environment, and its exception flags were clear.
+        unsafe { env.restore() };
+    }
+}
+
+/// Run `f`, which changes the given bits of the environment on purpose, and save the new values
+/// of those bits as part of the armed environment.
+pub(crate) fn allow_change<R>(mxcsr: Mxcsr, control: X87Control, f: impl FnOnce() -> R) -> R {
+    let Some(mut env) = ARMED_ENV.take() else {
+        return f();
+    };
+
+    let result = f();
+
+    let live = FpEnv::save();
+    env.mxcsr = env.mxcsr.difference(mxcsr).union(live.mxcsr & mxcsr);
+    env.control = env
+        .control
+        .difference(control)
+        .union(live.control & control);
+    ARMED_ENV.set(Some(env));
+
+    result
+}
+
+/// The bits of the environment that changed since the thread was armed.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Changes {
+    armed: FpEnv,
+    live: FpEnv,
+}
+
+impl Changes {
+    fn between(armed: FpEnv, live: FpEnv) -> Option<Self> {
+        let changed = (armed.mxcsr ^ live.mxcsr).intersects(MXCSR_WATCHED)
+            || (armed.control ^ live.control).intersects(FCW_WATCHED);
+
+        changed.then_some(Self { armed, live })
+    }
+}
+
+/// Formats the bits that were set and cleared in each register, e.g.
+/// `MXCSR set Mxcsr(FTZ), cleared Mxcsr(ZM)`. This does not allocate.
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mxcsr_set = (self.live.mxcsr - self.armed.mxcsr) & MXCSR_WATCHED;
+        let mxcsr_cleared = (self.armed.mxcsr - self.live.mxcsr) & MXCSR_WATCHED;
+        let fcw_set = (self.live.control - self.armed.control) & FCW_WATCHED;
+        let fcw_cleared = (self.armed.control - self.live.control) & FCW_WATCHED;
+
+        let mut separator = "";
+        if !mxcsr_set.is_empty() || !mxcsr_cleared.is_empty() {
+            write!(f, "MXCSR")?;
+            write_bits(f, mxcsr_set, mxcsr_cleared)?;
+            separator = "; ";
+        }
+        if !fcw_set.is_empty() || !fcw_cleared.is_empty() {
+            write!(f, "{separator}x87 control word")?;
+            write_bits(f, fcw_set, fcw_cleared)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Write the bits that were set and cleared in one register.
+fn write_bits<T>(f: &mut fmt::Formatter<'_>, set: T, cleared: T) -> fmt::Result
+where
+    T: fmt::Debug + Default + Eq,
+{
+    let mut separator = " ";
+    if set != T::default() {
+        write!(f, "{separator}set {set:?}")?;
+        separator = ", ";
+    }
+    if cleared != T::default() {
+        write!(f, "{separator}cleared {cleared:?}")?;
+    }
+
+    Ok(())
+}
@@ -34,52 +34,336 @@
 //! The 64-bit media floating-point instructions do not generate floating-point exceptions. Software
 //! must ensure that in-range operands are provided to these instructions.
 
-// This is not actually dead code?
-// Needed because cargo check doesn't notice we are using the constants in global_asm!()
-#![allow(dead_code)]
-
-use core::arch::global_asm;
-
-/// Zero-Divide Exception Mask (x87)
-const FCW_ZM: u16 = 1 << 2;
-/// Invalid Operation Exception Mask (x87)
-const FCW_IM: u16 = 1 << 0;
-/// Unmask (enable) x87 exceptions
-const FCW_UNMASK: u16 = !(FCW_ZM | FCW_IM);
-
-/// Zero-Divide Exception Mask (SSE)
-const MXCSR_ZM: u32 = 1 << 9;
-/// Invalid Operation Exception Mask (SSE)
-const MXCSR_IM: u32 = 1 << 7;
-/// Zero-Divide Exception (SSE)
-const MXCSR_ZE: u32 = 1 << 2;
-/// Invalid Operation Exception (SSE)
-const MXCSR_IE: u32 = 1 << 0;
-/// Clear and unmask (enable) SSE FP exceptions
-const MXCSR_UNMASK: u32 = !(MXCSR_ZM | MXCSR_IM | MXCSR_ZE | MXCSR_IE);
-
-global_asm!(
-    ".global enable_fp_exceptions",
-    "enable_fp_exceptions:",
-
-    // Clear and enable SSE FP exceptions
-    "stmxcsr    dword ptr [rsp-4]",
-    "and        dword ptr [rsp-4], {mxcsr}",
-    "ldmxcsr    dword ptr [rsp-4]",
-
-    // Clear and enable x87 exceptions
-    "fclex",
-    "fstcw      word ptr [rsp-8]",
-    "and        word ptr [rsp-8], {fcw}",
-    "fldcw      word ptr [rsp-8]",
-
-    "ret",
-
-    mxcsr = const MXCSR_UNMASK,
-    fcw = const FCW_UNMASK,
-);
-
-extern "C" {
-    /// Enable floating point exceptions on the current thread.
-    pub fn enable_fp_exceptions();
+#[cfg(debug_assertions)]
+use crate::backend::{FpuBackend, Saved};
+use crate::fenv::{Mxcsr, X87Control};
+#[cfg(debug_assertions)]
+use crate::policy::Exceptions;
+use core::arch::asm;
+
+#[cfg(all(debug_assertions, target_os = "linux"))]
+mod context;
+#[cfg(all(debug_assertions, target_os = "linux"))]
+pub(crate) use context::*;
+
+/// Exception Masks (x87). The bit order matches [`Exceptions`].
+#[cfg(all(debug_assertions, target_os = "linux"))]
+const FCW_MASK_ALL: u16 = X87Control::MASKS.bits();
+
+/// Exception Flags (x87). The bit order matches [`Exceptions`].
+#[cfg(debug_assertions)]
+const FSW_FLAGS: u16 = crate::fenv::X87Status::FLAGS.bits();
+
+/// Exception Masks (SSE). The bit order matches [`Exceptions`].
+#[cfg(all(debug_assertions, target_os = "linux"))]
+const MXCSR_MASK_ALL: u32 = Mxcsr::MASKS.bits();
+/// Offset of the exception masks from the exception flags (SSE)
+#[cfg(debug_assertions)]
+const MXCSR_MASK_SHIFT: u32 = Mxcsr::IM.bits().trailing_zeros();
+/// Exception Flags (SSE). The bit order matches [`Exceptions`].
+#[cfg(debug_assertions)]
+const MXCSR_FLAGS: u32 = Mxcsr::FLAGS.bits();
+/// Denormals Are Zero (SSE)
+const MXCSR_DAZ: u32 = Mxcsr::DAZ.bits();
+/// Flush To Zero (SSE)
+const MXCSR_FTZ: u32 = Mxcsr::FTZ.bits();
+/// Offset of the Rounding Control field (SSE)
+const MXCSR_RC_SHIFT: u32 = Mxcsr::RC.bits().trailing_zeros();
+/// Rounding Control (SSE)
+const MXCSR_RC: u32 = Mxcsr::RC.bits();
+/// Offset of the Rounding Control field (x87)
+const FCW_RC_SHIFT: u16 = X87Control::RC.bits().trailing_zeros() as u16;
+/// Rounding Control (x87)
+const FCW_RC: u16 = X87Control::RC.bits();
+
+/// The SSE unit, configured with MXCSR.
+#[cfg(debug_assertions)]
+pub(crate) struct Sse;
+
+#[cfg(debug_assertions)]
+impl FpuBackend for Sse {
+    fn name(&self) -> &'static str {
+        "sse"
+    }
+
+    fn is_supported(&self) -> bool {
+        true
+    }
+
+    unsafe fn enable(&self, exceptions: Exceptions) {
+        let masks = u32::from(exceptions.bits()) << MXCSR_MASK_SHIFT;
+        ldmxcsr(stmxcsr() & !MXCSR_FLAGS & !masks);
+    }
+
+    unsafe fn disable(&self, exceptions: Exceptions) {
+        ldmxcsr(stmxcsr() | u32::from(exceptions.bits()) << MXCSR_MASK_SHIFT);
+    }
+
+    fn read_enabled(&self) -> Exceptions {
+        Exceptions::from_bits(!(stmxcsr() >> MXCSR_MASK_SHIFT) as u8)
+    }
+
+    fn save(&self) -> Saved {
+        Saved(u64::from(stmxcsr()))
+    }
+
+    unsafe fn restore(&self, saved: Saved) {
+        ldmxcsr(saved.0 as u32);
+    }
+
+    fn read_status(&self) -> Exceptions {
+        Exceptions::from_bits((stmxcsr() & MXCSR_FLAGS) as u8)
+    }
+
+    unsafe fn clear_status(&self) {
+        ldmxcsr(stmxcsr() & !MXCSR_FLAGS);
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn decode_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+        sse_raised_in_context(context)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn status_in_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+        sse_status_in_context(context)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn enabled_in_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+        sse_enabled_in_context(context)
+    }
+}
+
+/// The x87 FPU, configured with the x87 control word.
+#[cfg(debug_assertions)]
+pub(crate) struct X87;
+
+#[cfg(debug_assertions)]
+impl FpuBackend for X87 {
+    fn name(&self) -> &'static str {
+        "x87"
+    }
+
+    fn is_supported(&self) -> bool {
+        true
+    }
+
+    unsafe fn enable(&self, exceptions: Exceptions) {
+        fnclex_fldcw(fnstcw() & !u16::from(exceptions.bits()));
+    }
+
+    unsafe fn disable(&self, exceptions: Exceptions) {
+        fldcw(fnstcw() | u16::from(exceptions.bits()));
+    }
+
+    fn read_enabled(&self) -> Exceptions {
+        Exceptions::from_bits(!fnstcw() as u8)
+    }
+
+    fn save(&self) -> Saved {
+        Saved(u64::from(fnstsw()) << 16 | u64::from(fnstcw()))
+    }
+
+    /// The exception flags are cleared instead of restored. Restoring them would raise a pending
+    /// exception if any of them are unmasked.
+    unsafe fn restore(&self, saved: Saved) {
+        fnclex_fldcw(saved.0 as u16);
+    }
+
+    fn read_status(&self) -> Exceptions {
+        Exceptions::from_bits((fnstsw() & FSW_FLAGS) as u8)
+    }
+
+    unsafe fn clear_status(&self) {
+        asm!("fnclex", options(nomem, nostack, preserves_flags));
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn decode_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+        x87_raised_in_context(context)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn status_in_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+        x87_status_in_context(context)
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn enabled_in_context(&self, context: *mut libc::ucontext_t) -> Exceptions {
+        x87_enabled_in_context(context)
+    }
+}
+
+/// Read the SSE control and status register.
+///
+/// Returns zero on processors without SSE, where only the x87 FPU is available.
+pub(crate) fn stmxcsr() -> u32 {
+    let mut mxcsr = 0;
+    // SAFETY: Stores to a local.
+    #[cfg(target_feature = "sse")]
+    unsafe {
+        asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags))
+    };
+    mxcsr
+}
+
+/// Write the SSE control and status register.
+///
+/// # Safety
+///
+/// Changes the floating point environment of the current thread. Does nothing on processors
+/// without SSE.
+pub(crate) unsafe fn ldmxcsr(mxcsr: u32) {
+    #[cfg(target_feature = "sse")]
+    asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, preserves_flags));
+    #[cfg(not(target_feature = "sse"))]
+    let _ = mxcsr;
+}
+
+/// Read the x87 control word.
+pub(crate) fn fnstcw() -> u16 {
+    let mut fcw = 0;
+    // SAFETY: Stores to a local.
+    unsafe { asm!("fnstcw [{}]", in(reg) &mut fcw, options(nostack, preserves_flags)) };
+    fcw
+}
+
+/// Write the x87 control word.
+///
+/// # Safety
+///
+/// Changes the floating point environment of the current thread.
+pub(crate) unsafe fn fldcw(fcw: u16) {
+    asm!("fldcw [{}]", in(reg) &fcw, options(nostack, preserves_flags));
+}
+
+/// Read the x87 status word.
+pub(crate) fn fnstsw() -> u16 {
+    let mut fsw = 0;
+    // SAFETY: Stores to a local.
+    unsafe { asm!("fnstsw [{}]", in(reg) &mut fsw, options(nostack, preserves_flags)) };
+    fsw
+}
+
+/// Clear the x87 exception flags and write the x87 control word.
+///
+/// The exception flags must be cleared first, or an unmasked pending exception is raised by the
+/// next x87 instruction.
+///
+/// # Safety
+///
+/// Changes the floating point environment of the current thread.
+#[cfg(debug_assertions)]
+unsafe fn fnclex_fldcw(fcw: u16) {
+    asm!(
+        "fnclex",
+        "fldcw [{}]",
+        in(reg) &fcw,
+        options(nostack, preserves_flags),
+    );
+}
+
+/// Run `f`, which changes the given MXCSR and x87 control word bits on purpose.
+///
+/// The new values are expected by the watchdog in debug builds.
+fn expected_change(mxcsr: u32, fcw: u16, f: impl FnOnce()) {
+    #[cfg(debug_assertions)]
+    crate::watchdog::allow_change(Mxcsr::from_bits(mxcsr), X87Control::from_bits(fcw), f);
+
+    #[cfg(not(debug_assertions))]
+    {
+        let _ = (mxcsr, fcw);
+        f();
+    }
+}
+
+/// Sets the SSE denormal handling modes until it is dropped.
+///
+/// Dropping the guard restores the previous modes. The rest of the configuration is left alone,
+/// so exceptions that are enabled while the guard is alive stay enabled.
+pub(crate) struct DenormalGuard {
+    mxcsr: u32,
+}
+
+impl DenormalGuard {
+    pub(crate) fn new(flush_to_zero: bool, denormals_are_zero: bool) -> Self {
+        let mxcsr = stmxcsr();
+        let mut modes = 0;
+        if flush_to_zero {
+            modes |= MXCSR_FTZ;
+        }
+        if denormals_are_zero {
+            modes |= MXCSR_DAZ;
+        }
+
+        // SAFETY: The denormal handling modes only change the results of operations on
+        // subnormals.
+        expected_change(MXCSR_FTZ | MXCSR_DAZ, 0, || unsafe {
+            ldmxcsr(mxcsr & !(MXCSR_FTZ | MXCSR_DAZ) | modes)
+        });
+
+        Self {
+            mxcsr: mxcsr & (MXCSR_FTZ | MXCSR_DAZ),
+        }
+    }
+}
+
+impl Drop for DenormalGuard {
+    fn drop(&mut self) {
+        // SAFETY: This restores the modes that were saved by `DenormalGuard::new`.
+        expected_change(MXCSR_FTZ | MXCSR_DAZ, 0, || unsafe {
+            ldmxcsr(stmxcsr() & !(MXCSR_FTZ | MXCSR_DAZ) | self.mxcsr)
+        });
+    }
+}
+
+/// Sets the SSE and x87 rounding control until it is dropped.
+///
+/// `rc` is the 2-bit rounding control field, which has the same encoding for SSE and x87.
+/// Dropping the guard restores the previous rounding control. The rest of the configuration is
+/// left alone.
+pub(crate) struct RoundingGuard {
+    rc: u8,
+    mxcsr: u32,
+    fcw: u16,
+}
+
+impl RoundingGuard {
+    pub(crate) fn new(rc: u8) -> Self {
+        let mxcsr = stmxcsr();
+        let fcw = fnstcw();
+
+        // SAFETY: The rounding control only changes the results of inexact operations.
+        expected_change(MXCSR_RC, FCW_RC, || unsafe {
+            ldmxcsr(mxcsr & !MXCSR_RC | u32::from(rc) << MXCSR_RC_SHIFT);
+            fldcw(fcw & !FCW_RC | u16::from(rc) << FCW_RC_SHIFT);
+        });
+
+        Self {
+            rc,
+            mxcsr: mxcsr & MXCSR_RC,
+            fcw: fcw & FCW_RC,
+        }
+    }
+
+    /// Returns `true` if the rounding control is still the one that was set by
+    /// [`RoundingGuard::new`].
+    pub(crate) fn is_unchanged(&self) -> bool {
+        let sse = (stmxcsr() & MXCSR_RC) >> MXCSR_RC_SHIFT;
+        let x87 = (fnstcw() & FCW_RC) >> FCW_RC_SHIFT;
+
+        (sse == u32::from(self.rc) || cfg!(not(target_feature = "sse")))
+            && x87 == u16::from(self.rc)
+    }
+}
+
+impl Drop for RoundingGuard {
+    fn drop(&mut self) {
+        // SAFETY: This restores the rounding control that was saved by `RoundingGuard::new`.
+        expected_change(MXCSR_RC, FCW_RC, || unsafe {
+            ldmxcsr(stmxcsr() & !MXCSR_RC | self.mxcsr);
+            fldcw(fnstcw() & !FCW_RC | self.fcw);
+        });
+    }
 }
@@ -0,0 +1,691 @@
+//! Access to the FPU state of an interrupted thread, from a signal handler context.
+//!
+//! The kernel saves the FPU state in the signal frame, and restores it from there when the signal
+//! handler returns. This is the only way for a signal handler to inspect or change the FPU
+//! configuration of the interrupted thread.
+//!
+//! The layout differs between `x86_64` and `x86`. On `x86_64`, the frame is in the `FXSAVE`
+//! (or `XSAVE`) format. On `x86`, an `FSAVE` format header precedes the `FXSAVE` area. The kernel
+//! restores the x87 environment from that header, so x87 registers are read and written there.
+//! The `FXSAVE` area is absent when the processor does not support it; only the x87 registers are
+//! available in that case.
+
+use super::{FCW_MASK_ALL, FSW_FLAGS, MXCSR_FLAGS, MXCSR_MASK_ALL, MXCSR_MASK_SHIFT};
+use crate::fenv::{FpEnv, Mxcsr, X87Control, X87Status};
+use crate::policy::Exceptions;
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86 as arch;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64 as arch;
+
+/// x87 status word exception flags, stack fault, error summary, and busy bits cleared by `fclex`.
+const FSW_CLEAR: u16 = 0x80ff;
+/// Error Summary (x87). Set when an unmasked exception is pending.
+const FSW_ES: u16 = 1 << 7;
+/// Trap Flag (EFLAGS)
+const EFLAGS_TF: libc::greg_t = 1 << 8;
+
+/// Offset of the `_fpx_sw_bytes` magic number in the `FXSAVE` area.
+const FP_XSTATE_MAGIC1_OFFSET: usize = 464;
+/// Magic number indicating the signal frame FPU state is in XSAVE format.
+const FP_XSTATE_MAGIC1: u32 = 0x4650_5853;
+/// Offset of the `_fpx_sw_bytes` field containing the size of the XSAVE area.
+const XSTATE_SIZE_OFFSET: usize = 480;
+/// Offset of the XSAVE header `XSTATE_BV` field.
+const XSTATE_BV_OFFSET: usize = 512;
+/// `XSTATE_BV` bit for x87 state.
+const XSTATE_BV_X87: u64 = 1 << 0;
+/// `XSTATE_BV` bit for the upper halves of the YMM registers.
+const XSTATE_BV_YMM: u64 = 1 << 2;
+/// Offset of MXCSR in the `FXSAVE` area.
+const FXSAVE_MXCSR_OFFSET: usize = 24;
+/// Offset of XMM0 in the `FXSAVE` area.
+const FXSAVE_XMM_OFFSET: usize = 160;
+
+/// x87 control word initial value.
+#[cfg(target_arch = "x86_64")]
+const FCW_INIT: u16 = 0x037f;
+
+/// Size of the `FSAVE` format header that precedes the `FXSAVE` area.
+#[cfg(target_arch = "x86")]
+const FSAVE_SIZE: usize = 112;
+/// Offset of the field in the `FSAVE` format header that indicates whether the `FXSAVE` area is
+/// present.
+#[cfg(target_arch = "x86")]
+const FSAVE_MAGIC_OFFSET: usize = 110;
+/// `FSAVE` header magic number indicating that the `FXSAVE` area is present.
+#[cfg(target_arch = "x86")]
+const X86_FXSR_MAGIC: u16 = 0x0000;
+
+/// The number of XMM registers.
+#[cfg(target_arch = "x86")]
+pub(crate) const XMM_REGISTERS: usize = 8;
+#[cfg(target_arch = "x86_64")]
+pub(crate) const XMM_REGISTERS: usize = 16;
+
+/// The instruction pointer in `mcontext_t::gregs`.
+#[cfg(target_arch = "x86")]
+pub(crate) const REG_IP: libc::c_int = libc::REG_EIP;
+#[cfg(target_arch = "x86_64")]
+pub(crate) const REG_IP: libc::c_int = libc::REG_RIP;
+
+/// The stack pointer in `mcontext_t::gregs`.
+#[cfg(target_arch = "x86")]
+const REG_SP: libc::c_int = libc::REG_ESP;
+#[cfg(target_arch = "x86_64")]
+const REG_SP: libc::c_int = libc::REG_RSP;
+
+/// The frame pointer in `mcontext_t::gregs`.
+#[cfg(all(feature = "frame-pointers", target_arch = "x86"))]
+const REG_FP: libc::c_int = libc::REG_EBP;
+#[cfg(all(feature = "frame-pointers", target_arch = "x86_64"))]
+const REG_FP: libc::c_int = libc::REG_RBP;
+
+/// The general purpose registers in `mcontext_t`.
+#[cfg(target_arch = "x86")]
+pub(crate) type Gregs = [libc::greg_t; 19];
+#[cfg(target_arch = "x86_64")]
+pub(crate) type Gregs = [libc::greg_t; 23];
+
+/// Exception masks saved from a signal handler context.
+#[derive(Clone, Copy)]
+pub(crate) struct Masks {
+    mxcsr: u32,
+    fcw: u16,
+}
+
+/// The FPU registers in a signal frame.
+struct FpState<'a> {
+    fcw: &'a mut u16,
+    fsw: &'a mut u16,
+    ftw: u16,
+    fop: u16,
+    fip: u64,
+    fdp: u64,
+    /// The `FXSAVE` area, which contains MXCSR and the XMM registers.
+    fxsave: Option<*mut u8>,
+}
+
+impl FpState<'_> {
+    fn mxcsr(&mut self) -> Option<&mut u32> {
+        // SAFETY: The `FXSAVE` area is valid for the lifetime of the signal frame.
+        self.fxsave
+            .map(|fxsave| unsafe { &mut *fxsave.add(FXSAVE_MXCSR_OFFSET).cast::<u32>() })
+    }
+}
+
+/// Get the FPU registers from a signal handler context.
+///
+/// `XRSTOR` ignores the legacy x87 region when the x87 state component is marked as being in its
+/// initial configuration. The component is marked as in use (with its initial configuration) so
+/// that changes to the x87 control word are restored when the signal handler returns.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+#[cfg(target_arch = "x86_64")]
+unsafe fn fp_state<'a>(context: *mut libc::ucontext_t) -> Option<FpState<'a>> {
+    let fpregs = (*context).uc_mcontext.fpregs;
+    if fpregs.is_null() {
+        return None;
+    }
+
+    let fxsave = fpregs.cast::<u8>();
+    if mark_x87_in_use(fxsave) {
+        (*fpregs).cwd = FCW_INIT;
+        (*fpregs).swd = 0;
+        (*fpregs).ftw = 0;
+        (*fpregs).fop = 0;
+        (*fpregs).rip = 0;
+        (*fpregs).rdp = 0;
+        (*fpregs)._st = std::mem::zeroed();
+    }
+
+    let fpregs = &mut *fpregs;
+    Some(FpState {
+        fcw: &mut fpregs.cwd,
+        fsw: &mut fpregs.swd,
+        ftw: fpregs.ftw,
+        fop: fpregs.fop,
+        fip: fpregs.rip,
+        fdp: fpregs.rdp,
+        fxsave: Some(fxsave),
+    })
+}
+
+/// Get the FPU registers from a signal handler context.
+///
+/// The kernel converts the x87 environment in the `FSAVE` format header to the `FXSAVE` format
+/// when the signal handler returns, so `XSTATE_BV` only needs to mark the x87 state as in use.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+#[cfg(target_arch = "x86")]
+unsafe fn fp_state<'a>(context: *mut libc::ucontext_t) -> Option<FpState<'a>> {
+    let fpregs = (*context).uc_mcontext.fpregs;
+    if fpregs.is_null() {
+        return None;
+    }
+
+    let base = fpregs.cast::<u8>();
+    let fxsave = (base.add(FSAVE_MAGIC_OFFSET).cast::<u16>().read() == X86_FXSR_MAGIC)
+        .then(|| base.add(FSAVE_SIZE));
+    if let Some(fxsave) = fxsave {
+        mark_x87_in_use(fxsave);
+    }
+
+    // The x87 environment fields are 32 bits wide. The control and status words are in the low
+    // halves, and the opcode is in the high half of the code segment selector.
+    let fpregs = &mut *fpregs;
+    Some(FpState {
+        fcw: &mut *(&mut fpregs.cw as *mut libc::c_ulong).cast::<u16>(),
+        fsw: &mut *(&mut fpregs.sw as *mut libc::c_ulong).cast::<u16>(),
+        ftw: fpregs.tag as u16,
+        fop: (fpregs.cssel >> 16) as u16 & 0x7ff,
+        fip: u64::from(fpregs.ipoff),
+        fdp: u64::from(fpregs.dataoff),
+        fxsave,
+    })
+}
+
+/// Mark the x87 state component as in use in an `XSAVE` format signal frame, returning `true` if
+/// it was in its initial configuration.
+///
+/// # Safety
+///
+/// `fxsave` must point to the `FXSAVE` area of a signal frame.
+unsafe fn mark_x87_in_use(fxsave: *mut u8) -> bool {
+    if read::<u32>(fxsave, FP_XSTATE_MAGIC1_OFFSET) != FP_XSTATE_MAGIC1 {
+        return false;
+    }
+
+    let xstate_bv = read::<u64>(fxsave, XSTATE_BV_OFFSET);
+    if xstate_bv & XSTATE_BV_X87 != 0 {
+        return false;
+    }
+    fxsave
+        .add(XSTATE_BV_OFFSET)
+        .cast::<u64>()
+        .write_unaligned(xstate_bv | XSTATE_BV_X87);
+
+    true
+}
+
+/// Read a field from the `FXSAVE` area.
+///
+/// # Safety
+///
+/// `fxsave` must point to the `FXSAVE` area of a signal frame, and the field must be in it.
+unsafe fn read<T>(fxsave: *mut u8, offset: usize) -> T {
+    fxsave.add(offset).cast::<T>().read_unaligned()
+}
+
+/// Clear and enable floating point exceptions in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn enable_in_context(context: *mut libc::ucontext_t, exceptions: Exceptions) {
+    if let Some(mut state) = fp_state(context) {
+        let bits = exceptions.bits();
+        if let Some(mxcsr) = state.mxcsr() {
+            *mxcsr &= !MXCSR_FLAGS & !(u32::from(bits) << MXCSR_MASK_SHIFT);
+        }
+        *state.fsw &= !FSW_CLEAR;
+        *state.fcw &= !u16::from(bits);
+    }
+}
+
+/// Get the floating point environment in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn fenv_in_context(context: *mut libc::ucontext_t) -> Option<FpEnv> {
+    let mut state = fp_state(context)?;
+    let mxcsr = state.mxcsr().map_or(0, |mxcsr| *mxcsr);
+
+    Some(FpEnv {
+        mxcsr: Mxcsr::from_bits(mxcsr),
+        control: X87Control::from_bits(*state.fcw),
+        status: X87Status::from_bits(*state.fsw),
+    })
+}
+
+/// Get the unmasked SSE exceptions that were raised in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn sse_raised_in_context(context: *mut libc::ucontext_t) -> Exceptions {
+    let sse = fp_state(context)
+        .and_then(|mut state| state.mxcsr().copied())
+        .map_or(0, |mxcsr| mxcsr & MXCSR_FLAGS & !(mxcsr >> MXCSR_MASK_SHIFT));
+
+    Exceptions::from_bits(sse as u8)
+}
+
+/// Get the unmasked x87 exceptions that were raised in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn x87_raised_in_context(context: *mut libc::ucontext_t) -> Exceptions {
+    let x87 = fp_state(context).map_or(0, |state| *state.fsw & FSW_FLAGS & !*state.fcw);
+
+    Exceptions::from_bits(x87 as u8)
+}
+
+/// Get the unmasked SSE exceptions in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn sse_enabled_in_context(context: *mut libc::ucontext_t) -> Exceptions {
+    let sse = fp_state(context)
+        .and_then(|mut state| state.mxcsr().copied())
+        .map_or(0, |mxcsr| !(mxcsr >> MXCSR_MASK_SHIFT));
+
+    Exceptions::from_bits(sse as u8)
+}
+
+/// Get the unmasked x87 exceptions in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn x87_enabled_in_context(context: *mut libc::ucontext_t) -> Exceptions {
+    let x87 = fp_state(context).map_or(0, |state| !*state.fcw);
+
+    Exceptions::from_bits(x87 as u8)
+}
+
+/// Get the SSE exception flags in a signal handler context, whether or not they are masked.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn sse_status_in_context(context: *mut libc::ucontext_t) -> Exceptions {
+    let sse = fp_state(context)
+        .and_then(|mut state| state.mxcsr().copied())
+        .map_or(0, |mxcsr| mxcsr & MXCSR_FLAGS);
+
+    Exceptions::from_bits(sse as u8)
+}
+
+/// Get the x87 exception flags in a signal handler context, whether or not they are masked.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn x87_status_in_context(context: *mut libc::ucontext_t) -> Exceptions {
+    let x87 = fp_state(context).map_or(0, |state| *state.fsw & FSW_FLAGS);
+
+    Exceptions::from_bits(x87 as u8)
+}
+
+/// Mask all floating point exceptions in a signal handler context, returning the previous masks.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn mask_in_context(context: *mut libc::ucontext_t) -> Option<Masks> {
+    let mut state = fp_state(context)?;
+    let mut masks = Masks {
+        mxcsr: 0,
+        fcw: *state.fcw,
+    };
+
+    if let Some(mxcsr) = state.mxcsr() {
+        masks.mxcsr = *mxcsr;
+        *mxcsr |= MXCSR_MASK_ALL;
+    }
+    *state.fcw |= FCW_MASK_ALL;
+
+    Some(masks)
+}
+
+/// Restore exception masks in a signal handler context and clear all exception flags.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn restore_in_context(context: *mut libc::ucontext_t, masks: Masks) {
+    if let Some(mut state) = fp_state(context) {
+        if let Some(mxcsr) = state.mxcsr() {
+            *mxcsr = masks.mxcsr & !MXCSR_FLAGS;
+        }
+        *state.fsw &= !FSW_CLEAR;
+        *state.fcw = masks.fcw;
+    }
+}
+
+/// Set or clear the trap flag in a signal handler context.
+///
+/// When the trap flag is set, the CPU raises `SIGTRAP` after executing one instruction.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn single_step_in_context(context: *mut libc::ucontext_t, enable: bool) {
+    let eflags = &mut (*context).uc_mcontext.gregs[libc::REG_EFL as usize];
+    if enable {
+        *eflags |= EFLAGS_TF;
+    } else {
+        *eflags &= !EFLAGS_TF;
+    }
+}
+
+/// Get the instruction pointer from a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn ip_in_context(context: *const libc::ucontext_t) -> usize {
+    (*context).uc_mcontext.gregs[REG_IP as usize] as usize
+}
+
+/// Set the instruction pointer in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn set_ip_in_context(context: *mut libc::ucontext_t, ip: usize) {
+    (*context).uc_mcontext.gregs[REG_IP as usize] = ip as libc::greg_t;
+}
+
+/// Get the stack pointer from a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn sp_in_context(context: *const libc::ucontext_t) -> usize {
+    (*context).uc_mcontext.gregs[REG_SP as usize] as usize
+}
+
+/// Set the stack pointer in a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn set_sp_in_context(context: *mut libc::ucontext_t, sp: usize) {
+    (*context).uc_mcontext.gregs[REG_SP as usize] = sp as libc::greg_t;
+}
+
+/// Get the frame pointer from a signal handler context.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+#[cfg(feature = "frame-pointers")]
+pub(crate) unsafe fn fp_in_context(context: *const libc::ucontext_t) -> usize {
+    (*context).uc_mcontext.gregs[REG_FP as usize] as usize
+}
+
+/// Get the address of the instruction that raised the exception in a signal handler context.
+///
+/// Unmasked x87 exceptions are not raised by the instruction that caused them. They are pending
+/// until the next x87 instruction that checks for them (e.g. `fwait`), which is where the
+/// instruction pointer is. The x87 FPU instruction pointer (FIP) is used instead, which is the
+/// address of the last x87 instruction that could raise an exception.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn fault_address_in_context(context: *mut libc::ucontext_t) -> usize {
+    match fp_state(context) {
+        Some(state) if is_x87_pending(*state.fsw, *state.fcw, state.fip) => state.fip as usize,
+        _ => ip_in_context(context),
+    }
+}
+
+/// Returns `true` if an unmasked x87 exception is pending, and the FIP is known.
+fn is_x87_pending(fsw: u16, fcw: u16, fip: u64) -> bool {
+    fsw & FSW_ES != 0 && fsw & FSW_FLAGS & !fcw != 0 && fip != 0
+}
+
+/// Floating point registers saved from a signal handler context, for the crash report.
+#[derive(Clone, Copy)]
+pub(crate) struct Registers {
+    /// Zero if the `FXSAVE` area is not available.
+    pub(crate) mxcsr: u32,
+    fcw: u16,
+    fsw: u16,
+    /// Tag word. This is the abridged tag word (one bit per register) on `x86_64`.
+    ftw: u16,
+    fop: u16,
+    fip: u64,
+    fdp: u64,
+    /// `None` if the `FXSAVE` area is not available.
+    xmm: Option<[u128; XMM_REGISTERS]>,
+    /// The upper halves of the YMM registers, when AVX state is saved.
+    ymm_hi: Option<[u128; XMM_REGISTERS]>,
+    /// General purpose registers, for decoding the faulting instruction. Not printed.
+    pub(crate) gregs: Gregs,
+}
+
+impl Registers {
+    /// Get the little-endian contents of a YMM register. The upper half is zero if AVX state is
+    /// not available.
+    pub(crate) fn ymm(&self, index: usize) -> Option<[u8; 32]> {
+        let mut bytes = [0; 32];
+        bytes[..16].copy_from_slice(&self.xmm?[index].to_le_bytes());
+        if let Some(high) = self.ymm_hi {
+            bytes[16..].copy_from_slice(&high[index].to_le_bytes());
+        }
+
+        Some(bytes)
+    }
+
+    /// Get the address and opcode of the x87 instruction that raised the exception, if it was
+    /// raised by an x87 instruction. See [`fault_address_in_context`].
+    pub(crate) fn x87_fault(&self) -> Option<(usize, u16)> {
+        is_x87_pending(self.fsw, self.fcw, self.fip).then_some((self.fip as usize, self.fop))
+    }
+}
+
+/// Copy the floating point registers from a signal handler context.
+///
+/// This is async-signal-safe.
+///
+/// # Safety
+///
+/// `context` must be the `ucontext_t` pointer passed to an `SA_SIGINFO` signal handler.
+pub(crate) unsafe fn registers_in_context(context: *mut libc::ucontext_t) -> Option<Registers> {
+    let mut state = fp_state(context)?;
+    let mxcsr = state.mxcsr().map_or(0, |mxcsr| *mxcsr);
+
+    let mut xmm = None;
+    let mut ymm_hi = None;
+    if let Some(fxsave) = state.fxsave {
+        xmm = Some(read(fxsave, FXSAVE_XMM_OFFSET));
+
+        // The YMM state component is at the same offset in the (non-compacted) XSAVE area of
+        // every signal frame, which is reported by CPUID leaf 0Dh sub-leaf 2.
+        if read::<u32>(fxsave, FP_XSTATE_MAGIC1_OFFSET) == FP_XSTATE_MAGIC1 {
+            let xstate_bv = read::<u64>(fxsave, XSTATE_BV_OFFSET);
+            let xstate_size = read::<u32>(fxsave, XSTATE_SIZE_OFFSET);
+            let offset = arch::__cpuid_count(0xd, 2).ebx as usize;
+            let size = core::mem::size_of::<[u128; XMM_REGISTERS]>();
+
+            if offset == 0 {
+                // AVX is not supported.
+            } else if xstate_bv & XSTATE_BV_YMM != 0 && offset + size <= xstate_size as usize {
+                ymm_hi = Some(read(fxsave, offset));
+            } else {
+                // The upper halves are in their initial configuration.
+                ymm_hi = Some([0; XMM_REGISTERS]);
+            }
+        }
+    }
+
+    Some(Registers {
+        mxcsr,
+        fcw: *state.fcw,
+        fsw: *state.fsw,
+        ftw: state.ftw,
+        fop: state.fop,
+        fip: state.fip,
+        fdp: state.fdp,
+        xmm,
+        ymm_hi,
+        gregs: (*context).uc_mcontext.gregs,
+    })
+}
+
+impl std::fmt::Display for Registers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.xmm.is_some() {
+            let mxcsr_masks = (self.mxcsr >> MXCSR_MASK_SHIFT) as u8;
+            writeln!(
+                f,
+                "  mxcsr: {:#010x} (flags: {}, unmasked: {})",
+                self.mxcsr,
+                Flags(self.mxcsr as u8),
+                Flags(!mxcsr_masks),
+            )?;
+        }
+        writeln!(
+            f,
+            "  fcw:   {:#06x} (unmasked: {})",
+            self.fcw,
+            Flags(!self.fcw as u8),
+        )?;
+        writeln!(
+            f,
+            "  fsw:   {:#06x} (flags: {})",
+            self.fsw,
+            Flags(self.fsw as u8)
+        )?;
+        writeln!(f, "  ftw:   {:#06x}", self.ftw)?;
+        writeln!(f, "  fop:   {:#06x}", self.fop)?;
+        writeln!(f, "  fip:   {:#018x}", self.fip)?;
+        writeln!(f, "  fdp:   {:#018x}", self.fdp)?;
+
+        let Some(xmm) = self.xmm else {
+            return Ok(());
+        };
+        for (i, &low) in xmm.iter().enumerate() {
+            let halves = [low, self.ymm_hi.map_or(0, |high| high[i])];
+            let (name, halves) = match self.ymm_hi {
+                Some(_) => ("ymm", &halves[..]),
+                None => ("xmm", &halves[..1]),
+            };
+
+            let label = format!("{name}{i}:");
+            write!(f, "  {label:<6} 0x")?;
+            for (j, half) in halves.iter().rev().enumerate() {
+                if j > 0 {
+                    f.write_str("_")?;
+                }
+                write!(f, "{half:032x}")?;
+            }
+            writeln!(f)?;
+
+            let f32s = halves.iter().flat_map(|half| {
+                (0..4).map(move |lane| f32::from_bits((half >> (lane * 32)) as u32))
+            });
+            let f64s = halves.iter().flat_map(|half| {
+                (0..2).map(move |lane| f64::from_bits((half >> (lane * 64)) as u64))
+            });
+            writeln!(f, "         f32: {:?}", f32s.collect::<Vec<_>>())?;
+            writeln!(f, "         f64: {:?}", f64s.collect::<Vec<_>>())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// SSE arithmetic operations that can be evaluated one lane at a time.
+#[cfg(target_feature = "sse2")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Operation {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Sqrt,
+    Min,
+    Max,
+}
+
+/// Execute a two-operand SSE instruction.
+#[cfg(target_feature = "sse2")]
+macro_rules! sse {
+    ($op:literal, $suffix:literal, $a:ident, $b:ident) => {
+        core::arch::asm!(
+            concat!($op, $suffix, " {}, {}"),
+            inout(xmm_reg) $a,
+            in(xmm_reg) $b,
+            options(nomem, nostack),
+        )
+    };
+}
+
+#[cfg(target_feature = "sse2")]
+macro_rules! evaluate {
+    ($name:ident, $ty:ty, $suffix:literal) => {
+        /// Evaluate one lane of an SSE operation with the rounding and denormal modes of `mxcsr`,
+        /// returning the result and the exceptions that it raised. All exceptions are masked.
+        ///
+        /// `Sqrt` ignores `a`.
+        pub(crate) fn $name(operation: Operation, a: $ty, b: $ty, mxcsr: u32) -> ($ty, Exceptions) {
+            let saved = super::stmxcsr();
+            let mut result = a;
+
+            // SAFETY: All exceptions are masked, and the configuration is restored afterward.
+            unsafe {
+                super::ldmxcsr((mxcsr | MXCSR_MASK_ALL) & !MXCSR_FLAGS);
+                match operation {
+                    Operation::Add => sse!("add", $suffix, result, b),
+                    Operation::Sub => sse!("sub", $suffix, result, b),
+                    Operation::Mul => sse!("mul", $suffix, result, b),
+                    Operation::Div => sse!("div", $suffix, result, b),
+                    Operation::Sqrt => sse!("sqrt", $suffix, result, b),
+                    Operation::Min => sse!("min", $suffix, result, b),
+                    Operation::Max => sse!("max", $suffix, result, b),
+                }
+            }
+
+            let raised = Exceptions::from_bits(super::stmxcsr() as u8);
+            // SAFETY: Restores the configuration that was saved above.
+            unsafe { super::ldmxcsr(saved) };
+
+            (result, raised)
+        }
+    };
+}
+
+#[cfg(target_feature = "sse2")]
+evaluate!(evaluate_f32, f32, "ss");
+#[cfg(target_feature = "sse2")]
+evaluate!(evaluate_f64, f64, "sd");
+
+/// Exception flags or masks, formatted with their x86 mnemonics.
+struct Flags(u8);
+
+impl std::fmt::Display for Flags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const NAMES: [&str; 6] = ["IE", "DE", "ZE", "OE", "UE", "PE"];
+
+        let mut names = NAMES
+            .iter()
+            .enumerate()
+            .filter(|&(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, name)| name)
+            .peekable();
+        if names.peek().is_none() {
+            return f.write_str("none");
+        }
+        for (i, name) in names.enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+//! These test subnormal detection and flushing.
+
+use batman::{Action, DenormalMode, Exception};
+use rusty_forkfork::rusty_fork_test;
+use std::hint::black_box;
+
+const SUBNORMAL: f64 = f64::MIN_POSITIVE / 4.0;
+
+#[test]
+fn test_flush_to_zero() {
+    let result = batman::with_denormals(DenormalMode::FlushToZero, || {
+        black_box(f64::MIN_POSITIVE) / black_box(4.0)
+    });
+    assert_eq!(result, 0.0);
+
+    // Subnormal operands are not affected.
+    let result = batman::with_denormals(DenormalMode::FlushToZero, || {
+        black_box(SUBNORMAL) * black_box(4.0)
+    });
+    assert_eq!(result, f64::MIN_POSITIVE);
+
+    // The previous mode is restored.
+    assert_eq!(black_box(f64::MIN_POSITIVE) / black_box(4.0), SUBNORMAL);
+}
+
+#[test]
+fn test_denormals_are_zero() {
+    let result = batman::with_denormals(DenormalMode::DenormalsAreZero, || {
+        black_box(SUBNORMAL) * black_box(4.0)
+    });
+    assert_eq!(result, 0.0);
+
+    let result = batman::with_denormals(DenormalMode::FlushAll, || {
+        // Nested calls restore the outer mode.
+        let preserved = batman::with_denormals(DenormalMode::Preserve, || {
+            black_box(SUBNORMAL) * black_box(4.0)
+        });
+        assert_eq!(preserved, f64::MIN_POSITIVE);
+
+        black_box(SUBNORMAL) * black_box(4.0)
+    });
+    assert_eq!(result, 0.0);
+}
+
+rusty_fork_test! {
+    #[test]
+    fn test_count_denormal_sites() -> std::io::Result<()> {
+        batman::set_action(Exception::Denormal, Action::Count)?;
+        unsafe { batman::signal()? };
+
+        for _ in 0..3 {
+            assert_eq!(black_box(SUBNORMAL) * black_box(4.0), f64::MIN_POSITIVE);
+        }
+        assert_eq!(batman::count(Exception::Denormal), 3);
+
+        let sites = batman::sites();
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].exception, Exception::Denormal);
+        assert_eq!(sites[0].count, 3);
+
+        // Subnormal operands are not detected when they are treated as zero.
+        batman::with_denormals(DenormalMode::DenormalsAreZero, || {
+            assert_eq!(black_box(SUBNORMAL) * black_box(4.0), 0.0);
+        });
+        assert_eq!(batman::count(Exception::Denormal), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! These test the typed floating point environment registers.
+
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+use batman::fenv::{FpEnv, Mxcsr, X87Control, X87Status};
+use std::hint::black_box;
+
+#[test]
+fn test_debug_names_bits() {
+    let mxcsr = Mxcsr::from_bits(0x1f80);
+    assert_eq!(mxcsr, Mxcsr::MASKS);
+    assert_eq!(format!("{mxcsr:?}"), "Mxcsr(IM | DM | ZM | OM | UM | PM)");
+
+    // The top of stack field does not have a name.
+    let status = X87Status::from_bits(0x3801);
+    assert_eq!(status.top(), 7);
+    assert_eq!(format!("{status:?}"), "X87Status(IE | 0x3800)");
+    assert_eq!(format!("{:?}", X87Control::empty()), "X87Control(0x0)");
+}
+
+#[test]
+#[cfg(target_feature = "sse")]
+fn test_mxcsr_write() {
+    let saved = Mxcsr::read();
+    assert!(saved.contains(Mxcsr::MASKS));
+
+    unsafe { (saved | Mxcsr::FTZ).write() };
+    let tiny = black_box(f32::MIN_POSITIVE) / black_box(4.0);
+    let flushed = Mxcsr::read();
+    unsafe { saved.write() };
+
+    assert_eq!(tiny, 0.0);
+    assert!(flushed.contains(Mxcsr::FTZ));
+    assert!(!Mxcsr::read().contains(Mxcsr::FTZ));
+}
+
+#[test]
+#[cfg(target_feature = "sse")]
+fn test_restore_discards_flags() {
+    let saved = FpEnv::save();
+
+    let quotient = black_box(1.0_f64) / black_box(0.0);
+    assert!(quotient.is_infinite());
+    assert!(Mxcsr::read().contains(Mxcsr::ZE));
+
+    unsafe { saved.restore() };
+    assert_eq!(FpEnv::save().mxcsr, saved.mxcsr);
+}
+
+#[test]
+fn test_restore_control() {
+    let saved = FpEnv::save();
+
+    let mut control = saved.control;
+    control.set(X87Control::RC, true);
+    unsafe { control.write() };
+    assert_ne!(FpEnv::save(), saved);
+
+    unsafe { saved.restore() };
+    assert_eq!(FpEnv::save().control, saved.control);
+}
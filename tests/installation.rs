@@ -0,0 +1,74 @@
+//! These test the installation checks. The checks look at process-wide state, so every test runs
+//! in a child process.
+
+use rusty_forkfork::rusty_fork_test;
+
+rusty_fork_test! {
+    #[test]
+    fn test_installation_ok() -> std::io::Result<()> {
+        unsafe { batman::signal()? };
+
+        batman::verify_installation()
+    }
+
+    #[test]
+    fn test_installation_not_installed() {
+        let err = batman::verify_installation().unwrap_err().to_string();
+
+        assert!(err.contains("exceptions are not enabled on this thread"), "{err}");
+        assert!(err.contains("the SIGFPE handler is not installed"), "{err}");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_installation_blocked_and_replaced() -> std::io::Result<()> {
+        unsafe { batman::signal()? };
+
+        unsafe {
+            let mut mask: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut mask);
+            libc::sigaddset(&mut mask, libc::SIGFPE);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut());
+            libc::signal(libc::SIGFPE, libc::SIG_IGN);
+        }
+
+        let err = batman::verify_installation().unwrap_err().to_string();
+        assert!(err.contains("SIGFPE is blocked in the signal mask of this thread"), "{err}");
+        assert!(err.contains("was replaced by SIG_IGN"), "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    fn test_installation_existing_handler() -> std::io::Result<()> {
+        // Another library handles SIGFPE before `batman` is installed.
+        unsafe { libc::signal(libc::SIGFPE, libc::SIG_IGN) };
+        unsafe { batman::signal()? };
+
+        let err = batman::suppress("libfoo::kernel*").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported, "{err}");
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_installation_masked() -> std::io::Result<()> {
+        use batman::fenv::{Mxcsr, X87Control};
+
+        unsafe { batman::signal()? };
+
+        // Another library masks every exception.
+        unsafe {
+            (Mxcsr::read() | Mxcsr::MASKS).write();
+            (X87Control::read() | X87Control::MASKS).write();
+        }
+
+        let err = batman::verify_installation().unwrap_err().to_string();
+        let expected = "exceptions are masked on this thread (invalid operation, division by zero)";
+        assert!(err.contains(expected), "{err}");
+
+        Ok(())
+    }
+}
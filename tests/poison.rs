@@ -0,0 +1,76 @@
+//! Signaling NaN poisoning tests.
+
+#![cfg_attr(debug_assertions, feature(allocator_api))]
+
+#[cfg(debug_assertions)]
+use batman::poison::PoisonAlloc;
+use batman::poison::{self, Float as _, Poisoned};
+use rusty_forkfork::rusty_fork_test;
+use std::hint::black_box;
+
+#[test]
+fn test_fill_is_signaling_nan() {
+    let mut samples = [0.0_f32; 5];
+    poison::fill(&mut samples);
+    assert!(samples.iter().all(|sample| sample.is_signaling_nan()));
+
+    let mut samples = [0.0_f64; 5];
+    poison::fill(&mut samples);
+    assert!(samples.iter().all(|sample| sample.is_signaling_nan()));
+}
+
+#[test]
+fn test_quiet_nan_is_not_signaling_nan() {
+    assert!(!f32::NAN.is_signaling_nan());
+    assert!(!f64::NAN.is_signaling_nan());
+    assert!(!f64::INFINITY.is_signaling_nan());
+    assert!(!1.0_f64.is_signaling_nan());
+}
+
+#[test]
+fn test_poisoned_vec_resize() {
+    let mut samples = Poisoned::<Vec<f64>>::with_len(2);
+    samples[0] = 1.0;
+    samples.resize(4);
+
+    assert_eq!(samples.len(), 4);
+    assert_eq!(samples[0].to_bits(), 1.0_f64.to_bits());
+    assert!(samples[1..].iter().all(|sample| sample.is_signaling_nan()));
+}
+
+#[test]
+fn test_poisoned_array_default() {
+    let samples = Poisoned::<[f32; 3]>::default();
+
+    assert!(samples.iter().all(|sample| sample.is_signaling_nan()));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn test_poison_alloc_grow() {
+    let mut samples = Vec::with_capacity_in(2, PoisonAlloc::<f32>::new());
+    samples.extend([1.0_f32, 2.0, 3.0]);
+
+    let len = samples.capacity();
+    // SAFETY: The allocator initialized the spare capacity.
+    unsafe { samples.set_len(len) };
+
+    assert_eq!(samples[2].to_bits(), 3.0_f32.to_bits());
+    assert!(samples[3..].iter().all(|sample| sample.is_signaling_nan()));
+}
+
+rusty_fork_test! {
+    #[test]
+    #[should_panic]
+    fn test_panic_read_poisoned() {
+        unsafe { batman::signal().unwrap() };
+
+        let mut samples = Poisoned::<[f64; 4]>::new();
+        samples[..3].copy_from_slice(&[1.0, 2.0, 3.0]);
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            black_box(samples).iter().sum::<f64>()
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! These test per-exception actions. Actions are process-wide, so every test runs in a child
+//! process.
+
+use batman::{Action, Exception};
+use rusty_forkfork::rusty_fork_test;
+use std::hint::black_box;
+
+rusty_fork_test! {
+    #[test]
+    fn test_count_overflow() -> std::io::Result<()> {
+        batman::set_action(Exception::Overflow, Action::Count)?;
+        unsafe { batman::signal()? };
+
+        assert!((black_box(f32::MAX) * black_box(2.0)).is_infinite());
+        assert!((black_box(f64::MAX) + black_box(f64::MAX)).is_infinite());
+        assert_eq!(batman::count(Exception::Overflow), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_warn_once_divide_by_zero() -> std::io::Result<()> {
+        batman::set_action(Exception::DivideByZero, Action::WarnOnce)?;
+        unsafe { batman::signal()? };
+
+        for _ in 0..3 {
+            assert!((black_box(1.0_f32) / black_box(0.0)).is_infinite());
+        }
+        assert_eq!(batman::count(Exception::DivideByZero), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_divide_by_zero() -> std::io::Result<()> {
+        batman::set_action(Exception::DivideByZero, Action::Ignore)?;
+        unsafe { batman::signal()? };
+
+        assert!((black_box(1.0_f32) / black_box(0.0)).is_infinite());
+        assert_eq!(batman::count(Exception::DivideByZero), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_panic_abort_invalid_operation() {
+        batman::set_action(Exception::DivideByZero, Action::Count).unwrap();
+        unsafe { batman::signal().unwrap() };
+
+        assert!((black_box(1.0_f32) / black_box(0.0)).is_infinite());
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            black_box(0.0_f32) / black_box(0.0)
+        );
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    fn test_panic_is_caught() -> std::io::Result<()> {
+        use std::panic::{self, AssertUnwindSafe};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static DROPPED: AtomicBool = AtomicBool::new(false);
+
+        struct Guard;
+
+        impl Drop for Guard {
+            fn drop(&mut self) {
+                DROPPED.store(true, Ordering::Relaxed);
+            }
+        }
+
+        batman::set_action(Exception::InvalidOperation, Action::Panic)?;
+        unsafe { batman::signal()? };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = Guard;
+            let nan = black_box(0.0_f64) / black_box(0.0);
+            eprintln!("ERROR: This should never be printed! {nan}");
+        }));
+
+        let payload = result.unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<String>().map(String::as_str),
+            Some("floating point exception: invalid operation")
+        );
+        assert!(DROPPED.load(Ordering::Relaxed));
+
+        // The thread is still armed.
+        let result = panic::catch_unwind(|| black_box(-1.0_f32).sqrt());
+        assert!(result.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn test_panic_is_not_raised_at_push_or_pop() -> std::io::Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        static REACHED: AtomicBool = AtomicBool::new(false);
+
+        batman::set_action(Exception::InvalidOperation, Action::Panic)?;
+        unsafe { batman::signal()? };
+
+        // The `push` and `pop` move the stack pointer like a call and a return to the next
+        // instruction, but the panic must wait for the return from the closure.
+        let result = std::panic::catch_unwind(|| {
+            let mut zero = black_box(0.0_f64);
+            unsafe {
+                std::arch::asm!(
+                    "lea {scratch}, [rip + 2f]",
+                    "divsd {zero}, {zero}",
+                    "push {scratch}",
+                    "2:",
+                    "pop {scratch}",
+                    "lea {scratch}, [rip + 3f]",
+                    "push {scratch}",
+                    "pop {scratch}",
+                    "3:",
+                    "mov byte ptr [rip + {reached}], 1",
+                    zero = inout(xmm_reg) zero,
+                    scratch = out(reg) _,
+                    reached = sym REACHED,
+                );
+            }
+            zero
+        });
+        assert!(result.is_err());
+        assert!(REACHED.load(Ordering::Relaxed));
+
+        Ok(())
+    }
+}
+
+#[test]
+#[cfg(not(debug_assertions))]
+fn test_panic_refused_without_debug_assertions() {
+    let err = batman::set_action(Exception::InvalidOperation, Action::Panic).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    assert_eq!(batman::action(Exception::InvalidOperation), Action::Abort);
+}
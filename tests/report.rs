@@ -0,0 +1,674 @@
+//! These test the contents of the crash report. Each test runs itself again in a child process
+//! that raises an exception, and checks what the child printed to `stderr`.
+
+use std::hint::black_box;
+use std::process::{Command, Output};
+use std::time::Duration;
+
+const CHILD_VAR: &str = "BATMAN_TEST_REPORT_CHILD";
+
+/// Run `test` in a child process with `child` as its body, returning the child's output.
+fn run_child(test: &str, child: fn()) -> Option<Output> {
+    run_child_with_backend(test, None, child)
+}
+
+/// Like [`run_child`], with `BATMAN_BACKEND` set in the child.
+fn run_child_with_backend(test: &str, backend: Option<&str>, child: fn()) -> Option<Output> {
+    if std::env::var_os(CHILD_VAR).is_some() {
+        child();
+        return None;
+    }
+
+    let mut command = Command::new(std::env::current_exe().unwrap());
+    if let Some(backend) = backend {
+        command.env("BATMAN_BACKEND", backend);
+    }
+    let output = command
+        .args(["--exact", test, "--nocapture", "--test-threads=1"])
+        .env(CHILD_VAR, "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+
+    Some(output)
+}
+
+#[test]
+#[cfg(target_feature = "sse2")]
+fn test_report_registers() {
+    let Some(output) = run_child("test_report_registers", || {
+        unsafe { batman::signal().unwrap() };
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            black_box(-1.5_f64) / black_box(0.0)
+        );
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(stderr.contains("Registers:"), "{stderr}");
+    assert!(stderr.contains("(flags: ZE, unmasked: IE ZE)"), "{stderr}");
+
+    // The operands are in the low lanes of two registers.
+    assert!(stderr.contains("f64: [-1.5, "), "{stderr}");
+    assert!(stderr.contains("f64: [0.0, "), "{stderr}");
+}
+
+#[test]
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse2"
+))]
+fn test_report_padding_lanes() {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::{_mm_div_ps, _mm_setr_ps, _mm_storeu_ps};
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::{_mm_div_ps, _mm_setr_ps, _mm_storeu_ps};
+
+    let Some(output) = run_child("test_report_padding_lanes", || {
+        unsafe { batman::signal().unwrap() };
+
+        // A vectorized loop over two elements, with the tail padded with zeros.
+        let mut quotients = [0.0_f32; 4];
+        unsafe {
+            let a = _mm_setr_ps(1.0, 2.0, 0.0, 0.0);
+            let b = _mm_setr_ps(2.0, 4.0, 0.0, 0.0);
+            _mm_storeu_ps(
+                quotients.as_mut_ptr(),
+                _mm_div_ps(black_box(a), black_box(b)),
+            );
+        }
+
+        eprintln!("ERROR: This should never be printed! {quotients:?}");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains("Faulting instruction: divps xmm"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("    lane 0: 1.0 / 2.0 = 0.5\n"), "{stderr}");
+    assert!(
+        stderr.contains("  * lane 2: 0.0 / 0.0 = NaN (invalid operation)\n"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("probably padding"), "{stderr}");
+}
+
+#[test]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn test_report_x87_instruction() {
+    let Some(output) = run_child("test_report_x87_instruction", || {
+        unsafe { batman::signal().unwrap() };
+
+        let mut quotient = 0.0_f64;
+        unsafe {
+            // `fdivp` raises the exception, and `fstp` delivers it.
+            std::arch::asm!(
+                "fld1",
+                "fldz",
+                "fdivp st(1), st",
+                "fstp qword ptr [{}]",
+                in(reg) &mut quotient,
+            );
+        }
+
+        eprintln!("ERROR: This should never be printed! {quotient}");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains("raised by the x87 instruction `de f9`"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("test_report_x87_instruction"), "{stderr}");
+}
+
+#[test]
+fn test_report_checkpoint() {
+    let Some(output) = run_child_with_backend("test_report_checkpoint", Some("polling"), || {
+        unsafe { batman::signal().unwrap() };
+        batman::checkpoint();
+
+        // Not trapped, because the exceptions are polled.
+        let quotient = black_box(1.0_f64) / black_box(0.0);
+        eprintln!("Polled: {quotient}");
+        batman::checkpoint();
+
+        eprintln!("ERROR: This should never be printed!");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(stderr.contains("Polled: inf"), "{stderr}");
+    assert!(
+        stderr.contains("(division by zero) occurred before the checkpoint at tests/report.rs:"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("(after the checkpoint at tests/report.rs:"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_report_near_stack_exhaustion() {
+    /// Recurse until less than `reserve` bytes of the stack are left, then divide by zero.
+    #[inline(never)]
+    fn recurse(bottom: usize, reserve: usize) -> f64 {
+        let frame = black_box([0_u8; 512]);
+        let sp = frame.as_ptr() as usize;
+        if sp - bottom < reserve {
+            return black_box(1.0_f64) / black_box(0.0);
+        }
+
+        recurse(bottom, reserve) + f64::from(frame[0])
+    }
+
+    let Some(output) = run_child("test_report_near_stack_exhaustion", || {
+        use batman::thread::BuilderExt;
+
+        let builder = std::thread::Builder::new().stack_size(256 * 1024);
+        let body = || {
+            let mut attr = unsafe { std::mem::zeroed() };
+            let mut bottom = std::ptr::null_mut();
+            let mut size = 0;
+            unsafe {
+                libc::pthread_getattr_np(libc::pthread_self(), &mut attr);
+                libc::pthread_attr_getstack(&attr, &mut bottom, &mut size);
+                libc::pthread_attr_destroy(&mut attr);
+            }
+
+            eprintln!(
+                "ERROR: This should never be printed! {}",
+                recurse(bottom as usize, 2048)
+            );
+        };
+        let worker = unsafe { builder.spawn_trapped(body) };
+        worker.unwrap().join().unwrap();
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(!stderr.contains("has overflowed its stack"), "{stderr}");
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_report_helper() {
+    let Some(output) = run_child("test_report_helper", || {
+        batman::start_helper().unwrap();
+        unsafe { batman::signal().unwrap() };
+
+        // The tracer thread would deadlock on the `stderr` lock.
+        std::mem::forget(std::io::stderr().lock());
+
+        println!(
+            "ERROR: This should never be printed! {}",
+            black_box(0.0_f64) / black_box(0.0)
+        );
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("ERROR"), "{stdout}");
+    assert_eq!(
+        stderr.matches("Floating point exception occurred.").count(),
+        1,
+        "{stderr}"
+    );
+    assert!(stderr.contains("report::test_report_helper"), "{stderr}");
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    assert!(stderr.contains("Registers:"), "{stderr}");
+}
+
+#[test]
+#[cfg(all(feature = "frame-pointers", target_os = "linux"))]
+fn test_report_frame_pointers() {
+    #[inline(never)]
+    fn divide(a: f64, b: f64) -> f64 {
+        black_box(a) / black_box(b)
+    }
+
+    let Some(output) = run_child("test_report_frame_pointers", || {
+        unsafe { batman::signal().unwrap() };
+
+        eprintln!("ERROR: This should never be printed! {}", divide(0.0, 0.0));
+    }) else {
+        return;
+    };
+
+    // The backtrace starts at the faulting instruction, without the signal handler's frames.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    let divide = stderr.find("test_report_frame_pointers::divide");
+    let test = stderr.find("report::test_report_frame_pointers\n");
+    assert!(divide.is_some() && divide < test, "{stderr}");
+    assert!(!stderr.contains("batman::handler"), "{stderr}");
+}
+
+#[test]
+#[cfg(all(feature = "frame-pointers", target_os = "linux", target_arch = "x86_64"))]
+fn test_report_frame_pointers_below_stack() {
+    let Some(output) = run_child("test_report_frame_pointers_below_stack", || {
+        unsafe { batman::signal().unwrap() };
+
+        // Code without frame pointers can leave any value in `rbp`. This one is below the stack
+        // pointer, in a page that is not readable, like the unmapped part of the main thread's
+        // stack.
+        let local = 0_u8;
+        let page = (std::ptr::addr_of!(local) as usize & !0xfff) - 0x10000;
+        unsafe {
+            assert_eq!(libc::mprotect(page as *mut _, 0x1000, libc::PROT_NONE), 0);
+            std::arch::asm!(
+                "push rbp",
+                "mov rbp, {fp}",
+                "divsd {x}, {x}",
+                "pop rbp",
+                fp = in(reg) page,
+                x = inout(xmm_reg) 0.0_f64 => _,
+            )
+        };
+        eprintln!("ERROR: This should never be printed!");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains("test_report_frame_pointers_below_stack"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_report_fatal_callbacks() {
+    let Some(output) = run_child("test_report_fatal_callbacks", || {
+        batman::on_fatal_with_timeout(Duration::from_millis(200), |_| loop {
+            std::thread::park();
+        });
+        batman::on_fatal(|report| eprintln!("Callback: {report}"));
+        unsafe { batman::signal().unwrap() };
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            black_box(0.0_f64) / black_box(0.0)
+        );
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    let timeout = stderr
+        .find("batman: a fatal callback did not finish within 200ms")
+        .expect(&stderr);
+    #[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+    let callback = stderr
+        .find("Callback: floating point exception (invalid operation)")
+        .expect(&stderr);
+    #[cfg(not(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64"))))]
+    let callback = stderr
+        .find("Callback: floating point exception")
+        .expect(&stderr);
+    assert!(timeout < callback, "{stderr}");
+}
+
+#[test]
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+fn test_report_panic() {
+    let Some(output) = run_child("test_report_panic", || {
+        batman::set_action(batman::Exception::DivideByZero, batman::Action::Panic).unwrap();
+        unsafe { batman::signal().unwrap() };
+
+        // The frame has a destructor, so it has landing pads.
+        let label = String::from("quotient");
+        eprintln!(
+            "ERROR: This should never be printed! {label}: {}",
+            black_box(1.0_f64) / black_box(0.0)
+        );
+    }) else {
+        return;
+    };
+
+    // The test harness reports the panic like any other failure.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(101), "{stderr}");
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        !stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("floating point exception: division by zero")
+            || stdout.contains("floating point exception: division by zero"),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn test_report_panic_step_budget() {
+    let Some(output) = run_child("test_report_panic_step_budget", || {
+        batman::set_action(batman::Exception::InvalidOperation, batman::Action::Panic).unwrap();
+        unsafe { batman::signal().unwrap() };
+
+        // A loop without calls after the exception, so there is nowhere to panic.
+        let mut x = 0.0_f64;
+        unsafe {
+            std::arch::asm!(
+                "divsd {x}, {x}",
+                "2:",
+                "dec {n}",
+                "jnz 2b",
+                x = inout(xmm_reg) x,
+                n = inout(reg) 1_000_000_usize => _,
+            )
+        };
+        eprintln!("ERROR: This should never be printed! {x}");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_ne!(output.status.code(), Some(101), "{stderr}");
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(stderr.contains("did not reach a call or return"), "{stderr}");
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+fn test_report_integer_division_is_forwarded() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let Some(output) = run_child("test_report_integer_division_is_forwarded", || {
+        unsafe { batman::signal().unwrap() };
+
+        // Rust checks for division by zero, but C does not.
+        unsafe {
+            std::arch::asm!(
+                "div {divisor:e}",
+                divisor = in(reg) black_box(0_u32),
+                inout("eax") 1_u32 => _,
+                inout("edx") 0_u32 => _,
+            );
+        }
+
+        eprintln!("ERROR: This should never be printed!");
+    }) else {
+        return;
+    };
+
+    // The default action terminates the process with `SIGFPE` instead of `SIGKILL`.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        !stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+    assert_eq!(output.status.signal(), Some(libc::SIGFPE), "{stderr}");
+}
+
+#[test]
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+fn test_report_unarmed_thread_is_forwarded() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let Some(output) = run_child("test_report_unarmed_thread_is_forwarded", || {
+        use batman::fenv::Mxcsr;
+
+        unsafe { batman::signal().unwrap() };
+
+        // Other code traps exceptions on a thread that `batman` did not arm. The thread inherits
+        // the configuration that `batman` enabled, so it is replaced first.
+        std::thread::spawn(|| {
+            unsafe { ((Mxcsr::read() | Mxcsr::MASKS) - Mxcsr::ZM).write() };
+            eprintln!(
+                "ERROR: This should never be printed! {}",
+                black_box(1.0_f64) / black_box(0.0)
+            );
+        })
+        .join()
+        .unwrap();
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        !stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+    assert_eq!(output.status.signal(), Some(libc::SIGFPE), "{stderr}");
+}
+
+#[test]
+fn test_report_inherited_thread() {
+    let Some(output) = run_child("test_report_inherited_thread", || {
+        unsafe { batman::signal().unwrap() };
+
+        // The thread is not armed, but it inherits the configuration of this thread.
+        std::thread::spawn(|| {
+            eprintln!(
+                "ERROR: This should never be printed! {}",
+                black_box(0.0_f64) / black_box(0.0)
+            );
+        })
+        .join()
+        .unwrap();
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+fn test_report_verify() {
+    let Some(output) = run_child("test_report_verify", || {
+        use batman::fenv::Mxcsr;
+
+        unsafe { batman::signal().unwrap() };
+
+        // A library turns on FTZ behind our back.
+        unsafe { (Mxcsr::read() | Mxcsr::FTZ).write() };
+        batman::verify();
+
+        eprintln!("ERROR: This should never be printed!");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains(
+            "floating point environment changed (MXCSR set Mxcsr(FTZ)) before the checkpoint at \
+            tests/report.rs:"
+        ),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+fn test_report_rounding_verify() {
+    let Some(output) = run_child("test_report_rounding_verify", || {
+        use batman::fenv::Mxcsr;
+        use batman::RoundingMode;
+
+        unsafe { batman::signal().unwrap() };
+
+        // The change is reverted before the closure returns, but the checkpoint sees it.
+        batman::with_rounding(RoundingMode::TowardZero, || {
+            let mxcsr = Mxcsr::read();
+            unsafe { (mxcsr - Mxcsr::RC).write() };
+            batman::verify();
+            unsafe { mxcsr.write() };
+        });
+
+        eprintln!("ERROR: This should never be printed!");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains(
+            "floating point environment changed (MXCSR cleared Mxcsr(RC_DOWN | RC_UP)) before the \
+            checkpoint at tests/report.rs:"
+        ),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+fn test_report_sampler_verify() {
+    let Some(output) = run_child("test_report_sampler_verify", || {
+        use batman::fenv::Mxcsr;
+
+        unsafe { batman::signal().unwrap() };
+        batman::verify();
+
+        // The sampler finds the change without another checkpoint.
+        unsafe { (Mxcsr::read() | Mxcsr::DAZ).write() };
+        unsafe { batman::start_sampler(Duration::from_millis(10)).unwrap() };
+        for _ in 0..500 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        eprintln!("ERROR: This should never be printed!");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains(
+            "floating point environment changed (MXCSR set Mxcsr(DAZ)) after the checkpoint at \
+            tests/report.rs:"
+        ),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_report_sampler() {
+    let Some(output) = run_child_with_backend("test_report_sampler", Some("polling"), || {
+        unsafe { batman::signal().unwrap() };
+        batman::checkpoint();
+
+        let quotient = black_box(1.0_f64) / black_box(0.0);
+        eprintln!("Polled: {quotient}");
+
+        // The sampler finds the exception without another checkpoint.
+        unsafe { batman::start_sampler(Duration::from_millis(10)).unwrap() };
+        for _ in 0..500 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        eprintln!("ERROR: This should never be printed!");
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains("(division by zero) occurred after the checkpoint at tests/report.rs:"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
+
+#[test]
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn test_report_glibc_backend() {
+    let Some(output) = run_child_with_backend("test_report_glibc_backend", Some("glibc"), || {
+        unsafe { batman::signal().unwrap() };
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            black_box(0.0_f64) / black_box(0.0)
+        );
+    }) else {
+        return;
+    };
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("ERROR"), "{stderr}");
+    assert!(
+        stderr.contains("Floating point exception occurred."),
+        "{stderr}"
+    );
+}
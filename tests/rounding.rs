@@ -0,0 +1,53 @@
+//! These test scoped rounding modes.
+
+use batman::RoundingMode;
+use std::hint::black_box;
+
+fn third() -> f64 {
+    black_box(1.0) / black_box(3.0)
+}
+
+#[test]
+fn test_directed_rounding() {
+    let nearest = batman::with_rounding(RoundingMode::ToNearest, third);
+    let lower = batman::with_rounding(RoundingMode::TowardNegative, third);
+    let upper = batman::with_rounding(RoundingMode::TowardPositive, third);
+    let truncated = batman::with_rounding(RoundingMode::TowardZero, third);
+
+    assert!(lower < upper);
+    assert_eq!(lower, truncated);
+    assert!(nearest == lower || nearest == upper);
+
+    // Negative results round the other way toward zero.
+    let truncated = batman::with_rounding(RoundingMode::TowardZero, || -third());
+    assert_eq!(truncated, -lower);
+}
+
+#[test]
+fn test_nested_rounding() {
+    let (inner, outer) = batman::with_rounding(RoundingMode::TowardPositive, || {
+        let inner = batman::with_rounding(RoundingMode::TowardNegative, third);
+        (inner, third())
+    });
+
+    assert!(inner < outer);
+    assert_eq!(third(), batman::with_rounding(RoundingMode::ToNearest, third));
+}
+
+#[test]
+#[cfg(all(
+    debug_assertions,
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse"
+))]
+#[should_panic(expected = "rounding mode changed")]
+fn test_panic_rounding_changed() {
+    batman::with_rounding(RoundingMode::TowardZero, || {
+        let mut mxcsr = 0_u32;
+        unsafe {
+            std::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr);
+            mxcsr &= !(0x3 << 13);
+            std::arch::asm!("ldmxcsr [{}]", in(reg) &mxcsr);
+        }
+    });
+}
@@ -103,3 +103,94 @@ rusty_fork_test! {
         );
     }
 }
+
+rusty_fork_test! {
+    #[test]
+    #[should_panic]
+    fn test_panic_spawn_trapped_zero_div_zero() {
+        let handle = unsafe { batman::thread::spawn(|| black_box(0.0_f32) / black_box(0.0)) };
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            handle.unwrap().join().unwrap()
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_panic_builder_spawn_trapped_neg_sqrt() {
+        use batman::thread::BuilderExt as _;
+
+        let builder = std::thread::Builder::new().name("trapped".into());
+        let handle = unsafe { builder.spawn_trapped(|| black_box(-1.0_f32).sqrt()) };
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            handle.unwrap().join().unwrap()
+        );
+    }
+}
+
+rusty_fork_test! {
+    #[test]
+    #[should_panic]
+    fn test_panic_signal_all_threads_existing_thread() {
+        use std::sync::{Arc, Barrier};
+
+        let barrier = Arc::new(Barrier::new(2));
+        let handle = std::thread::spawn({
+            let barrier = barrier.clone();
+            move || {
+                barrier.wait();
+                barrier.wait();
+                black_box(0.0_f32) / black_box(0.0)
+            }
+        });
+
+        // Wait for the thread to start before enabling exceptions everywhere.
+        barrier.wait();
+        unsafe { batman::signal_all_threads().unwrap() };
+        barrier.wait();
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            handle.join().unwrap()
+        );
+    }
+}
+
+rusty_fork_test! {
+    #[test]
+    #[should_panic]
+    fn test_panic_after_unguarded() {
+        unsafe { batman::signal().unwrap() };
+
+        assert!(batman::unguarded(|| black_box(0.0_f32) / black_box(0.0)).is_nan());
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            black_box(1.0) / black_box(0.0)
+        );
+    }
+}
+
+rusty_fork_test! {
+    #[test]
+    #[should_panic]
+    fn test_panic_after_suppressed() {
+        #[inline(never)]
+        fn suppressed_kernel(a: f32, b: f32) -> f32 {
+            a / b
+        }
+
+        batman::suppress("*::suppressed_kernel").unwrap();
+        unsafe { batman::signal().unwrap() };
+
+        assert!(suppressed_kernel(black_box(0.0), black_box(0.0)).is_nan());
+
+        eprintln!(
+            "ERROR: This should never be printed! {}",
+            black_box(1.0) / black_box(0.0)
+        );
+    }
+}
@@ -2,6 +2,8 @@
 //!
 //! This just tests our assumptions that nothing fishy is happening with floating point operations.
 
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+use rusty_forkfork::rusty_fork_test;
 use std::hint::black_box;
 
 #[test]
@@ -88,3 +90,105 @@ fn test_pass_nothing_up_our_sleeves_sqrt() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_pass_unguarded_zero_div_zero() -> std::io::Result<()> {
+    unsafe { batman::signal()? };
+
+    assert!(batman::unguarded(|| black_box(0.0_f32) / black_box(0.0)).is_nan());
+
+    Ok(())
+}
+
+#[test]
+fn test_pass_unguarded_nested_neg_sqrt() -> std::io::Result<()> {
+    unsafe { batman::signal()? };
+
+    let (outer, inner) = batman::unguarded(|| {
+        let inner = batman::unguarded(|| black_box(-1.0_f32).sqrt());
+        (black_box(f32::INFINITY) - black_box(f32::INFINITY), inner)
+    });
+    assert!(outer.is_nan());
+    assert!(inner.is_nan());
+
+    Ok(())
+}
+
+#[test]
+fn test_pass_checkpoint_after_finite_division() -> std::io::Result<()> {
+    unsafe { batman::signal()? };
+
+    assert!(black_box(20.0) / black_box(5.0) - 4.0 <= f32::EPSILON);
+    batman::checkpoint();
+
+    Ok(())
+}
+
+#[test]
+fn test_pass_verify_expected_changes() -> std::io::Result<()> {
+    use batman::{DenormalMode, RoundingMode};
+
+    unsafe { batman::signal()? };
+
+    batman::with_denormals(DenormalMode::FlushAll, batman::verify);
+    batman::with_rounding(RoundingMode::TowardZero, batman::verify);
+    batman::unguarded(batman::verify);
+    batman::verify();
+
+    Ok(())
+}
+
+// `signal_all_threads` arms the other tests' threads, so this runs in a child process.
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+rusty_fork_test! {
+    #[test]
+    fn test_pass_signal_all_threads_during_unguarded() {
+        use std::sync::{Arc, Barrier};
+
+        let barrier = Arc::new(Barrier::new(2));
+        let handle = std::thread::spawn({
+            let barrier = barrier.clone();
+            move || {
+                batman::unguarded(|| {
+                    barrier.wait();
+                    barrier.wait();
+                    black_box(0.0_f32) / black_box(0.0)
+                })
+            }
+        });
+
+        // The thread is inside `unguarded` when every thread is armed.
+        barrier.wait();
+        unsafe { batman::signal_all_threads().unwrap() };
+        barrier.wait();
+
+        assert!(handle.join().unwrap().is_nan());
+    }
+}
+
+#[inline(never)]
+fn suppressed_kernel(a: f32, b: f32) -> f32 {
+    a / b
+}
+
+#[test]
+fn test_pass_suppressed_symbol_zero_div_zero() -> std::io::Result<()> {
+    batman::suppress("should_pass::suppressed_*")?;
+    unsafe { batman::signal()? };
+
+    assert!(suppressed_kernel(black_box(0.0), black_box(0.0)).is_nan());
+    assert!(suppressed_kernel(black_box(1.0), black_box(0.0)).is_infinite());
+
+    Ok(())
+}
+
+#[test]
+fn test_pass_suppressed_location_zero_div_zero() -> std::io::Result<()> {
+    unsafe { batman::signal()? };
+
+    batman::suppress(&format!("{}:{}", file!(), line!() + 1))?;
+    let quotient = black_box(0.0_f32) / black_box(0.0);
+    assert!(quotient.is_nan());
+
+    Ok(())
+}
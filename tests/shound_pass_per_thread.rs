@@ -28,3 +28,14 @@ fn test_pass_per_thread_racy_zero_div_zero() -> Result<(), Box<dyn Any + Send>>
 
     Ok(())
 }
+
+#[test]
+fn test_pass_spawn_trapped_parent_zero_div_zero() -> Result<(), Box<dyn Any + Send>> {
+    let handle = unsafe { batman::thread::spawn(|| black_box(20.0_f32) / black_box(5.0)) };
+    let quotient = handle.unwrap().join()?;
+
+    assert!(quotient - 4.0 <= f32::EPSILON);
+    assert!((black_box(0.0_f32) / black_box(0.0)).is_nan());
+
+    Ok(())
+}